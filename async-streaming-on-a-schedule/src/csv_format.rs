@@ -0,0 +1,210 @@
+use std::io::Write;
+
+use crate::{Interval, Numeric, PerformanceIndicators, Price};
+
+///
+/// Column names of the raw format, the same as the fields of `PerformanceIndicators`
+///
+pub const COLUMNS: [&str; 7] = [
+    "period_start",
+    "symbol",
+    "price",
+    "pct_change",
+    "period_min",
+    "period_max",
+    "last_sma",
+];
+
+///
+/// Parse a CSV delimiter from a single ASCII character, or `\t`/`tab` for tabs.
+///
+pub fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() && s != "\"" => Ok(s.as_bytes()[0]),
+        _ => Err(format!("'{}' is not a single ASCII character", s)),
+    }
+}
+
+///
+/// How `PerformanceIndicators` are written as CSV. Fields are quoted as in RFC 4180 when needed.
+/// Numbers are written raw unless `pretty` is set, which decorates them with `$` and `%` and
+/// rounds them to 2 decimals by default.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct CsvFormat {
+    pub delimiter: u8,
+    /// Number of decimals, all of them if `None`. Not applied to a raw `pct_change`
+    pub precision: Option<usize>,
+    pub pretty: bool,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        CsvFormat {
+            delimiter: b',',
+            precision: None,
+            pretty: false,
+        }
+    }
+}
+
+impl CsvFormat {
+    pub fn writer<W: Write>(&self, w: W) -> csv::Writer<W> {
+        csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .from_writer(w)
+    }
+
+    pub fn header(&self, interval: Interval) -> Vec<String> {
+        if self.pretty {
+            vec![
+                "period start".to_string(),
+                "symbol".to_string(),
+                "price".to_string(),
+                "change %".to_string(),
+                "min".to_string(),
+                "max".to_string(),
+                interval.sma_header(30),
+            ]
+        } else {
+            COLUMNS.iter().map(|c| c.to_string()).collect()
+        }
+    }
+
+    pub fn record(&self, indicators: &PerformanceIndicators) -> Vec<String> {
+        let percent = indicators.pct_change * Price::from_usize(100);
+        vec![
+            indicators.period_start.to_rfc3339(),
+            indicators.symbol.clone(),
+            self.price(indicators.price),
+            // a raw fraction isn't rounded, two decimals would already lose most of it
+            if self.pretty {
+                format!("{}%", self.number(percent))
+            } else {
+                indicators.pct_change.to_string()
+            },
+            self.price(indicators.period_min),
+            self.price(indicators.period_max),
+            self.price(indicators.last_sma),
+        ]
+    }
+
+    ///
+    /// A single record as a line of text, e.g. for stdout.
+    ///
+    pub fn line(&self, record: &[String]) -> String {
+        let mut writer = self.writer(vec![]);
+        let _ = writer.write_record(record);
+        let line = writer.into_inner().unwrap_or_default();
+        let line = String::from_utf8_lossy(&line);
+        // only the terminator, trailing whitespace in the last field is data
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        line.strip_suffix('\r').unwrap_or(line).to_string()
+    }
+
+    fn price(&self, value: Price) -> String {
+        if self.pretty {
+            format!("${}", self.number(value))
+        } else {
+            self.number(value)
+        }
+    }
+
+    fn number(&self, value: Price) -> String {
+        match (self.precision, self.pretty) {
+            (Some(precision), _) => format!("{:.*}", precision, round(value, precision)),
+            (None, true) => format!("{:.2}", round(value, 2)),
+            (None, false) => value.to_string(),
+        }
+    }
+}
+
+///
+/// `Decimal` truncates when formatted with a precision, so it's rounded (half to even) like `f64`.
+///
+#[cfg(feature = "decimal")]
+fn round(value: Price, decimals: usize) -> Price {
+    value.round_dp(decimals as u32)
+}
+
+#[cfg(not(feature = "decimal"))]
+fn round(value: Price, _decimals: usize) -> Price {
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use chrono::prelude::*;
+
+    fn indicators(symbol: &str) -> PerformanceIndicators {
+        PerformanceIndicators {
+            symbol: symbol.to_string(),
+            period_start: DateTime::from_timestamp(0, 0).unwrap(),
+            price: Price::from_f64(1.75).unwrap(),
+            pct_change: Price::from_f64(0.125).unwrap(),
+            period_min: Price::one(),
+            period_max: Price::from_usize(2),
+            last_sma: Price::from_f64(1.5).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_CsvFormat_line() {
+        let raw = CsvFormat::default();
+        assert_eq!(
+            raw.line(&raw.header(Interval::OneDay)),
+            "period_start,symbol,price,pct_change,period_min,period_max,last_sma"
+        );
+        assert_eq!(
+            raw.line(&raw.record(&indicators("AAPL"))),
+            "1970-01-01T00:00:00+00:00,AAPL,1.75,0.125,1,2,1.5"
+        );
+
+        let pretty = CsvFormat {
+            pretty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pretty.line(&pretty.header(Interval::FiveMinutes)),
+            "period start,symbol,price,change %,min,max,30x5m avg"
+        );
+        assert_eq!(
+            pretty.line(&pretty.record(&indicators("AAPL"))),
+            "1970-01-01T00:00:00+00:00,AAPL,$1.75,12.50%,$1.00,$2.00,$1.50"
+        );
+
+        let semicolons = CsvFormat {
+            delimiter: b';',
+            precision: Some(1),
+            pretty: false,
+        };
+        assert_eq!(
+            semicolons.line(&semicolons.record(&indicators("A;\"B\""))),
+            "1970-01-01T00:00:00+00:00;\"A;\"\"B\"\"\";1.8;0.125;1.0;2.0;1.5"
+        );
+        let pretty = CsvFormat {
+            precision: Some(1),
+            pretty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pretty.line(&pretty.record(&indicators("AAPL"))),
+            "1970-01-01T00:00:00+00:00,AAPL,$1.8,12.5%,$1.0,$2.0,$1.5"
+        );
+        assert_eq!(
+            raw.line(&["AAPL".to_string(), "trailing ".to_string()]),
+            "AAPL,trailing "
+        );
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(parse_delimiter(";"), Ok(b';'));
+        assert_eq!(parse_delimiter("tab"), Ok(b'\t'));
+        assert!(parse_delimiter("ab").is_err());
+        assert!(parse_delimiter("\"").is_err());
+    }
+}
@@ -1,10 +1,19 @@
-use std::io::{Error, ErrorKind};
+use std::{
+    io::{Error, ErrorKind},
+    iter::Sum,
+    ops::{Add, Div, Sub},
+};
 
 use chrono::prelude::{DateTime, Utc};
-use clap::Parser;
-use tokio::time;
+use clap::{Parser, ValueEnum};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::{signal, task::JoinSet, time};
 use yahoo_finance_api as yahoo;
 
+mod csv_format;
+use csv_format::{parse_delimiter, CsvFormat};
+
 #[derive(Parser, Debug, Clone)]
 #[clap(
     version = "1.0",
@@ -16,12 +25,114 @@ struct Opts {
     symbols: String,
     #[clap(short, long)]
     from: String,
+    /// The quote field to calculate the signals on, `close` with `--adjust total-return`
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = PriceField::Adjclose,
+        default_value_if("adjust", "total-return", "close")
+    )]
+    price_field: PriceField,
+    /// Adjustment applied to the price series
+    #[clap(long, value_enum, default_value_t = Adjustment::None)]
+    adjust: Adjustment,
+    /// The bar size of the quotes
+    #[clap(long, value_enum, default_value_t = Interval::OneDay)]
+    interval: Interval,
+    /// Format of the printed records
+    #[clap(long, value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+    /// Field delimiter of the CSV output, a single character or `tab`
+    #[clap(long, value_parser = parse_delimiter, default_value = ",")]
+    delimiter: u8,
+    /// Decimals of the numbers in the CSV output, all of them by default. A raw `pct_change` is
+    /// never rounded
+    #[clap(long)]
+    precision: Option<usize>,
+    /// Write `$` prices and `%` changes rounded to 2 decimals instead of raw numbers
+    #[clap(long)]
+    pretty: bool,
+    /// Seconds to wait for running downloads on Ctrl-C/SIGTERM
+    #[clap(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+}
+
+///
+/// The numeric type used for prices and signals. Enable the `decimal` feature for exact fixed-point
+/// arithmetic, e.g. to reconcile with accounting systems.
+///
+#[cfg(not(feature = "decimal"))]
+type Price = f64;
+#[cfg(feature = "decimal")]
+type Price = Decimal;
+
+///
+/// The numeric operations a signal needs to work on a series. Implemented for `f64` and for the
+/// fixed-point `Decimal`, which keeps the results exact.
+///
+trait Numeric:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Div<Output = Self>
+    + Sum
+    + Send
+    + Sync
+    + 'static
+{
+    fn zero() -> Self;
+
+    fn one() -> Self;
+
+    fn from_usize(n: usize) -> Self;
+
+    ///
+    /// Convert from an `f64`, `None` if the value can't be represented (e.g. `NaN`).
+    ///
+    fn from_f64(value: f64) -> Option<Self>;
+}
+
+impl Numeric for f64 {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn one() -> Self {
+        1.0
+    }
+
+    fn from_usize(n: usize) -> Self {
+        n as f64
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl Numeric for Decimal {
+    fn zero() -> Self {
+        Decimal::ZERO
+    }
+
+    fn one() -> Self {
+        Decimal::ONE
+    }
+
+    fn from_usize(n: usize) -> Self {
+        Decimal::from(n)
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        <Decimal as rust_decimal::prelude::FromPrimitive>::from_f64(value)
+    }
 }
 
 ///
 /// A trait to provide a common interface for all signal calculations.
 ///
-trait AsyncStockSignal {
+trait AsyncStockSignal<T: Numeric = f64> {
     ///
     /// The signal's data type.
     ///
@@ -34,27 +145,27 @@ trait AsyncStockSignal {
     ///
     /// The signal (using the provided type) or `None` on error/invalid data.
     ///
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType>;
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType>;
 }
 
 ///
-/// Calculates the absolute and relative difference between the beginning and ending of an f64 series.
+/// Calculates the absolute and relative difference between the beginning and ending of a series.
 /// The relative difference is relative to the beginning.
 ///
 struct PriceDifference {}
 
-impl AsyncStockSignal for PriceDifference {
+impl<T: Numeric> AsyncStockSignal<T> for PriceDifference {
     ///
     /// A tuple `(absolute, relative)` to represent a price difference.
     ///
-    type SignalType = (f64, f64);
+    type SignalType = (T, T);
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         if !series.is_empty() {
             // unwrap is safe here even if first == last
-            let (first, last) = (series.first().unwrap(), series.last().unwrap());
+            let (first, last) = (*series.first().unwrap(), *series.last().unwrap());
             let abs_diff = last - first;
-            let first = if *first == 0.0 { 1.0 } else { *first };
+            let first = if first == T::zero() { T::one() } else { first };
             let rel_diff = abs_diff / first;
             Some((abs_diff, rel_diff))
         } else {
@@ -70,15 +181,15 @@ struct WindowedSMA {
     pub window_size: usize,
 }
 
-impl AsyncStockSignal for WindowedSMA {
-    type SignalType = Vec<f64>;
+impl<T: Numeric> AsyncStockSignal<T> for WindowedSMA {
+    type SignalType = Vec<T>;
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         if !series.is_empty() && self.window_size > 1 {
             Some(
                 series
                     .windows(self.window_size)
-                    .map(|w| w.iter().sum::<f64>() / w.len() as f64)
+                    .map(|w| w.iter().copied().sum::<T>() / T::from_usize(w.len()))
                     .collect(),
             )
         } else {
@@ -88,46 +199,233 @@ impl AsyncStockSignal for WindowedSMA {
 }
 
 ///
-/// Find the maximum in a series of f64
+/// Find the maximum in a series
 ///
 struct MaxPrice {}
 
-impl AsyncStockSignal for MaxPrice {
-    type SignalType = f64;
+impl<T: Numeric> AsyncStockSignal<T> for MaxPrice {
+    type SignalType = T;
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
-        if series.is_empty() {
-            None
-        } else {
-            Some(series.iter().fold(f64::MIN, |acc, q| acc.max(*q)))
-        }
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
+        series
+            .iter()
+            .copied()
+            .reduce(|acc, q| if q > acc { q } else { acc })
     }
 }
 
 ///
-/// Find the maximum in a series of f64
+/// Find the minimum in a series
 ///
 struct MinPrice {}
 
-impl AsyncStockSignal for MinPrice {
-    type SignalType = f64;
+impl<T: Numeric> AsyncStockSignal<T> for MinPrice {
+    type SignalType = T;
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
-        if series.is_empty() {
-            None
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
+        series
+            .iter()
+            .copied()
+            .reduce(|acc, q| if q < acc { q } else { acc })
+    }
+}
+
+///
+/// The quote field a price series is built from.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum PriceField {
+    Close,
+    Adjclose,
+    Open,
+    /// `(high + low + close) / 3`
+    Typical,
+}
+
+impl PriceField {
+    fn of(&self, quote: &yahoo::Quote) -> f64 {
+        match self {
+            PriceField::Close => quote.close,
+            PriceField::Adjclose => quote.adjclose,
+            PriceField::Open => quote.open,
+            PriceField::Typical => (quote.high + quote.low + quote.close) / 3.0,
+        }
+    }
+}
+
+///
+/// Adjustments applied to a price series. Yahoo's prices are already split-adjusted, so splits
+/// need no extra step.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Adjustment {
+    None,
+    /// Reinvest dividends on their ex-date to get a total-return series
+    TotalReturn,
+}
+
+///
+/// Format of the printed records
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Csv,
+    /// JSON Lines (NDJSON), one object per record
+    #[value(alias = "ndjson")]
+    Jsonl,
+}
+
+///
+/// Performance indicators of a symbol over the requested period
+///
+#[derive(Debug, Clone, Serialize)]
+struct PerformanceIndicators {
+    symbol: String,
+    period_start: DateTime<Utc>,
+    price: Price,
+    pct_change: Price,
+    period_min: Price,
+    period_max: Price,
+    last_sma: Price,
+}
+
+impl PerformanceIndicators {
+    fn print(&self, format: OutputFormat, csv: &CsvFormat) {
+        match format {
+            OutputFormat::Csv => println!("{}", csv.line(&csv.record(self))),
+            // plain strings, numbers and dates always serialize
+            OutputFormat::Jsonl => println!("{}", serde_json::to_string(self).unwrap()),
+        }
+    }
+}
+
+impl Adjustment {
+    ///
+    /// Check that the adjustment can be applied to a field: Yahoo's `adjclose` already accounts for
+    /// dividends, reinvesting them again would count them twice.
+    ///
+    fn check(&self, field: PriceField) -> Result<(), String> {
+        if *self == Adjustment::TotalReturn && field == PriceField::Adjclose {
+            Err(
+                "adjclose already includes dividends, use total-return with another price \
+                 field like close"
+                    .to_string(),
+            )
         } else {
-            Some(series.iter().fold(f64::MAX, |acc, q| acc.min(*q)))
+            Ok(())
         }
     }
 }
 
 ///
-/// Retrieve data from a data source and extract the closing prices. Errors during download are mapped onto io::Errors as InvalidData.
+/// Extract the price series from quotes (sorted by time, asc) and apply the adjustment. Dividends
+/// are expected to be on the same share basis as the quotes.
+///
+fn price_series(
+    quotes: &[yahoo::Quote],
+    dividends: &[yahoo::Dividend],
+    field: PriceField,
+    adjustment: Adjustment,
+) -> Vec<f64> {
+    let mut factor = 1.0;
+    let mut previous: Option<&yahoo::Quote> = None;
+    quotes
+        .iter()
+        .map(|q| {
+            if let (Adjustment::TotalReturn, Some(prev)) = (adjustment, previous) {
+                let prev_price = field.of(prev);
+                if prev_price != 0.0 {
+                    for d in dividends
+                        .iter()
+                        .filter(|d| d.date > prev.timestamp && d.date <= q.timestamp)
+                    {
+                        factor *= 1.0 + d.amount / prev_price;
+                    }
+                }
+            }
+            previous = Some(q);
+            field.of(q) * factor
+        })
+        .collect()
+}
+
+///
+/// The bar size of the quotes. Signals are calculated over bars, so with intraday bars the moving
+/// average spans minutes or hours instead of days.
 ///
-async fn fetch_closing_data(
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum Interval {
+    #[value(name = "1m")]
+    OneMinute,
+    #[value(name = "5m")]
+    FiveMinutes,
+    #[value(name = "15m")]
+    FifteenMinutes,
+    #[value(name = "1h")]
+    OneHour,
+    #[default]
+    #[value(name = "1d")]
+    OneDay,
+}
+
+impl Interval {
+    ///
+    /// The interval parameter of the provider's API.
+    ///
+    fn as_str(&self) -> &'static str {
+        match self {
+            Interval::OneMinute => "1m",
+            Interval::FiveMinutes => "5m",
+            Interval::FifteenMinutes => "15m",
+            Interval::OneHour => "1h",
+            Interval::OneDay => "1d",
+        }
+    }
+
+    ///
+    /// How far back the provider serves bars of this size, `None` if there is no limit.
+    ///
+    fn max_history(&self) -> Option<chrono::Duration> {
+        match self {
+            Interval::OneMinute => Some(chrono::Duration::days(7)),
+            Interval::FiveMinutes | Interval::FifteenMinutes => Some(chrono::Duration::days(60)),
+            Interval::OneHour => Some(chrono::Duration::days(730)),
+            Interval::OneDay => None,
+        }
+    }
+
+    ///
+    /// Move `from` forward if it's further back than the provider keeps bars of this size.
+    ///
+    fn clamp_start(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> DateTime<Utc> {
+        match self.max_history() {
+            Some(max) if to - from > max => to - max,
+            _ => from,
+        }
+    }
+
+    ///
+    /// The CSV column header of a moving average over `window` bars, e.g. `30d avg` or `30x5m avg`.
+    ///
+    fn sma_header(&self, window: usize) -> String {
+        match self {
+            Interval::OneDay => format!("{window}d avg"),
+            _ => format!("{window}x{} avg", self.as_str()),
+        }
+    }
+}
+
+///
+/// Retrieve data from a data source and extract the (adjusted) price series. Errors during
+/// download are mapped onto io::Errors as InvalidData.
+///
+async fn fetch_price_data(
     symbol: &str,
     beginning: &DateTime<Utc>,
     end: &DateTime<Utc>,
+    interval: Interval,
+    field: PriceField,
+    adjustment: Adjustment,
 ) -> std::io::Result<Vec<f64>> {
     let provider = yahoo::YahooConnector::new();
 
@@ -141,7 +439,7 @@ async fn fetch_closing_data(
         <ErrorKind as Into<std::io::Error>>::into(ErrorKind::InvalidData)
     })?;
     let response = provider
-        .get_quote_history(symbol, start, end)
+        .get_quote_history_interval(symbol, start, end, interval.as_str())
         .await
         .map_err(|_| Error::from(ErrorKind::InvalidData))?;
     let mut quotes = response
@@ -149,17 +447,43 @@ async fn fetch_closing_data(
         .map_err(|_| Error::from(ErrorKind::InvalidData))?;
     if !quotes.is_empty() {
         quotes.sort_by_cached_key(|k| k.timestamp);
-        Ok(quotes.iter().map(|q| q.adjclose).collect())
+        let dividends = response.dividends().unwrap_or_default();
+        Ok(price_series(&quotes, &dividends, field, adjustment))
     } else {
         Ok(vec![])
     }
 }
 
+///
+/// Resolves on SIGINT (Ctrl-C) or SIGTERM.
+///
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let opts = Opts::parse();
+    opts.adjust
+        .check(opts.price_field)
+        .map_err(std::io::Error::other)?;
     let from: DateTime<Utc> = opts.from.parse().expect("Couldn't parse 'from' date");
-    let to = Utc::now();
+    let (bar_size, price_field, adjust, format) =
+        (opts.interval, opts.price_field, opts.adjust, opts.format);
+    let csv = CsvFormat {
+        delimiter: opts.delimiter,
+        precision: opts.precision,
+        pretty: opts.pretty,
+    };
 
     let symbols = opts
         .symbols
@@ -167,53 +491,95 @@ async fn main() -> std::io::Result<()> {
         .map(|s| s.trim().to_string())
         .collect::<Vec<_>>();
     let mut interval = time::interval(time::Duration::from_secs(30));
+    let mut downloads = JoinSet::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        interval.tick().await;
-        // a simple way to output a CSV header
-        println!("\nperiod start,symbol,price,change %,min,max,30d avg");
+        tokio::select! {
+            result = &mut shutdown => {
+                result?;
+                break;
+            }
+            // keep the set from growing with finished downloads
+            Some(_) = downloads.join_next(), if !downloads.is_empty() => continue,
+            _ = interval.tick() => {}
+        }
+        let to = Utc::now();
+        let from = bar_size.clamp_start(from, to);
+        if format == OutputFormat::Csv {
+            println!("\n{}", csv.line(&csv.header(bar_size)));
+        }
         for symbol in symbols.clone() {
-            tokio::spawn(async move {
-                match fetch_closing_data(&symbol, &from, &to).await {
-                    Ok(closes) if !closes.is_empty() => {
+            downloads.spawn(async move {
+                let prices = fetch_price_data(&symbol, &from, &to, bar_size, price_field, adjust)
+                    .await
+                    .map(|prices| {
+                        // dropping a price would shift the window, so the symbol is skipped instead
+                        prices
+                            .into_iter()
+                            .map(Numeric::from_f64)
+                            .collect::<Option<Vec<Price>>>()
+                    });
+                match prices {
+                    Ok(None) => eprintln!("Skipping '{}', a price can't be represented", symbol),
+                    Ok(Some(prices)) if !prices.is_empty() => {
                         let diff = PriceDifference {};
                         let min = MinPrice {};
                         let max = MaxPrice {};
                         let sma = WindowedSMA { window_size: 30 };
                         // min/max of the period. unwrap() because those are Option types
-                        let period_max = max.calculate(&closes).unwrap();
-                        let period_min = min.calculate(&closes).unwrap();
-                        let last_price = *closes.last().unwrap_or(&0.0);
-                        let (_, pct_change) = diff.calculate(&closes).unwrap_or((0.0, 0.0));
-                        let sma = sma.calculate(&closes).unwrap_or_default();
-
-                        // a simple way to output CSV data
-                        println!(
-                            "{},{},${:.2},{:.2}%,${:.2},${:.2},${:.2}",
-                            from.to_rfc3339(),
+                        let period_max = max.calculate(&prices).unwrap();
+                        let period_min = min.calculate(&prices).unwrap();
+                        let last_price = *prices.last().unwrap_or(&Price::zero());
+                        let (_, pct_change) = diff
+                            .calculate(&prices)
+                            .unwrap_or((Price::zero(), Price::zero()));
+                        let sma = sma.calculate(&prices).unwrap_or_default();
+
+                        PerformanceIndicators {
                             symbol,
-                            last_price,
-                            pct_change * 100.0,
+                            period_start: from,
+                            price: last_price,
+                            pct_change,
                             period_min,
                             period_max,
-                            sma.last().unwrap_or(&0.0)
-                        );
+                            last_sma: *sma.last().unwrap_or(&Price::zero()),
+                        }
+                        .print(format, &csv);
                     }
                     _ => {}
                 }
             });
         }
     }
+
+    // let running downloads finish, a second signal or the deadline aborts them
+    eprintln!(
+        "Shutting down, waiting for {} downloads. Press Ctrl-C again to exit right away",
+        downloads.len()
+    );
+    let deadline = time::Duration::from_secs(opts.shutdown_timeout);
+    tokio::select! {
+        result = time::timeout(deadline, async { while downloads.join_next().await.is_some() {} }) => {
+            if result.is_err() {
+                eprintln!("Downloads didn't finish within {}s", deadline.as_secs());
+            }
+        }
+        _ = shutdown_signal() => {}
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     #![allow(non_snake_case)]
     use super::*;
+    use rust_decimal_macros::dec;
 
     #[test]
     fn test_PriceDifference_calculate() {
         let signal = PriceDifference {};
-        assert_eq!(signal.calculate(&[]), None);
+        assert_eq!(signal.calculate(&[] as &[f64]), None);
         assert_eq!(signal.calculate(&[1.0]), Some((0.0, 0.0)));
         assert_eq!(signal.calculate(&[1.0, 0.0]), Some((-1.0, -1.0)));
         assert_eq!(
@@ -229,7 +595,7 @@ mod tests {
     #[test]
     fn test_MinPrice_calculate() {
         let signal = MinPrice {};
-        assert_eq!(signal.calculate(&[]), None);
+        assert_eq!(signal.calculate(&[] as &[f64]), None);
         assert_eq!(signal.calculate(&[1.0]), Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]), Some(0.0));
         assert_eq!(
@@ -245,7 +611,7 @@ mod tests {
     #[test]
     fn test_MaxPrice_calculate() {
         let signal = MaxPrice {};
-        assert_eq!(signal.calculate(&[]), None);
+        assert_eq!(signal.calculate(&[] as &[f64]), None);
         assert_eq!(signal.calculate(&[1.0]), Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]), Some(1.0));
         assert_eq!(
@@ -274,4 +640,111 @@ mod tests {
         let signal = WindowedSMA { window_size: 10 };
         assert_eq!(signal.calculate(&series), Some(vec![]));
     }
+
+    #[test]
+    fn test_signals_calculate_Decimal() {
+        let series = vec![dec!(2.0), dec!(4.5), dec!(5.3), dec!(6.5), dec!(4.7)];
+
+        let signal = WindowedSMA { window_size: 3 };
+        assert_eq!(
+            signal.calculate(&series),
+            Some(vec![
+                dec!(3.9333333333333333333333333333),
+                dec!(5.4333333333333333333333333333),
+                dec!(5.5)
+            ])
+        );
+        assert_eq!(
+            PriceDifference {}.calculate(&series),
+            Some((dec!(2.7), dec!(1.35)))
+        );
+        assert_eq!(MinPrice {}.calculate(&series), Some(dec!(2.0)));
+        assert_eq!(MaxPrice {}.calculate(&series), Some(dec!(6.5)));
+    }
+
+    #[test]
+    fn test_Numeric_from_f64() {
+        assert_eq!(<f64 as Numeric>::from_f64(1.5), Some(1.5));
+        assert_eq!(<Decimal as Numeric>::from_f64(1.5), Some(dec!(1.5)));
+        assert_eq!(<Decimal as Numeric>::from_f64(f64::NAN), None);
+    }
+
+    #[test]
+    fn test_price_series() {
+        let quote = |timestamp, close| yahoo::Quote {
+            timestamp,
+            open: close - 1.0,
+            high: close + 1.0,
+            low: close - 2.0,
+            volume: 100,
+            close,
+            adjclose: close / 2.0,
+        };
+        let quotes = vec![quote(1, 10.0), quote(2, 10.0), quote(3, 12.0)];
+        let dividends = vec![yahoo::Dividend {
+            amount: 1.0,
+            date: 2,
+        }];
+
+        assert_eq!(
+            price_series(&quotes, &dividends, PriceField::Close, Adjustment::None),
+            vec![10.0, 10.0, 12.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Adjclose, Adjustment::None),
+            vec![5.0, 5.0, 6.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Typical, Adjustment::TotalReturn),
+            vec![29.0 / 3.0, 29.0 / 3.0, 35.0 / 3.0]
+        );
+        assert_eq!(
+            price_series(
+                &quotes,
+                &dividends,
+                PriceField::Close,
+                Adjustment::TotalReturn
+            ),
+            vec![10.0, 11.0, 13.200000000000001]
+        );
+    }
+
+    #[test]
+    fn test_Interval() {
+        let to: DateTime<Utc> = "2024-02-01T16:00:00Z".parse().unwrap();
+        let from: DateTime<Utc> = "2023-01-01T00:00:00Z".parse().unwrap();
+
+        assert_eq!(Interval::OneDay.clamp_start(from, to), from);
+        assert_eq!(
+            Interval::OneMinute.clamp_start(from, to),
+            "2024-01-25T16:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(
+            Interval::OneHour.clamp_start(to - chrono::Duration::days(1), to),
+            to - chrono::Duration::days(1)
+        );
+        assert_eq!(Interval::OneDay.sma_header(30), "30d avg");
+        assert_eq!(Interval::FiveMinutes.sma_header(30), "30x5m avg");
+    }
+
+    #[test]
+    fn test_Adjustment_check() {
+        assert!(Adjustment::TotalReturn.check(PriceField::Close).is_ok());
+        assert!(Adjustment::TotalReturn.check(PriceField::Adjclose).is_err());
+        assert!(Adjustment::None.check(PriceField::Adjclose).is_ok());
+    }
+
+    #[test]
+    fn test_Opts_price_field() {
+        let parse = |args: &[&str]| {
+            Opts::parse_from(["app", "--from", "2020-01-01T00:00:00Z"].iter().chain(args))
+                .price_field
+        };
+        assert_eq!(parse(&[]), PriceField::Adjclose);
+        assert_eq!(parse(&["--adjust", "total-return"]), PriceField::Close);
+        assert_eq!(
+            parse(&["--adjust", "total-return", "--price-field", "open"]),
+            PriceField::Open
+        );
+    }
 }
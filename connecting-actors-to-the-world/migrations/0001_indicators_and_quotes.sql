@@ -0,0 +1,24 @@
+CREATE TABLE indicators (
+    symbol TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    price REAL NOT NULL,
+    pct_change REAL NOT NULL,
+    period_min REAL NOT NULL,
+    period_max REAL NOT NULL,
+    last_sma REAL NOT NULL,
+    PRIMARY KEY (symbol, timestamp)
+);
+CREATE INDEX indicators_timestamp ON indicators (timestamp);
+
+CREATE TABLE quotes (
+    symbol TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    open REAL NOT NULL,
+    high REAL NOT NULL,
+    low REAL NOT NULL,
+    close REAL NOT NULL,
+    adjclose REAL NOT NULL,
+    volume INTEGER NOT NULL,
+    PRIMARY KEY (symbol, timestamp)
+);
+CREATE INDEX quotes_timestamp ON quotes (timestamp);
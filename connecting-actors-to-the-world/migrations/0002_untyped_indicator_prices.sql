@@ -0,0 +1,16 @@
+-- Without a declared type, the prices are stored as they are bound: REAL, or the exact decimal as
+-- TEXT with the `decimal` feature, which a REAL column would convert to floating point.
+CREATE TABLE indicators_new (
+    symbol TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    price NOT NULL,
+    pct_change NOT NULL,
+    period_min NOT NULL,
+    period_max NOT NULL,
+    last_sma NOT NULL,
+    PRIMARY KEY (symbol, timestamp)
+);
+INSERT INTO indicators_new SELECT * FROM indicators;
+DROP TABLE indicators;
+ALTER TABLE indicators_new RENAME TO indicators;
+CREATE INDEX indicators_timestamp ON indicators (timestamp);
@@ -0,0 +1,170 @@
+use chrono::prelude::*;
+use serde::Serialize;
+use tide::{Body, Response, StatusCode};
+
+///
+/// The OpenAPI 3 specification of the HTTP API
+///
+pub const OPENAPI: &str = include_str!("../openapi.json");
+
+///
+/// Most records a single request may ask for
+///
+pub const MAX_RECORDS: usize = 1000;
+
+///
+/// The body of every JSON response: `data` on success, `error` otherwise
+///
+#[derive(Debug, Serialize)]
+pub struct Envelope<T> {
+    pub data: Option<T>,
+    pub error: Option<ApiError>,
+    pub meta: Meta,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    /// The HTTP status code
+    pub status: u16,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Meta {
+    /// When the response was created
+    pub timestamp: DateTime<Utc>,
+    /// Length of `data` if it's a list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+fn respond<T: Serialize>(
+    status: StatusCode,
+    data: Option<T>,
+    error: Option<ApiError>,
+    count: Option<usize>,
+) -> tide::Result {
+    let envelope = Envelope {
+        data,
+        error,
+        meta: Meta {
+            timestamp: Utc::now(),
+            count,
+        },
+    };
+    let mut response = Response::new(status);
+    response.set_body(Body::from_json(&envelope)?);
+    Ok(response)
+}
+
+///
+/// A response with `data` in an envelope.
+///
+pub fn data<T: Serialize>(status: StatusCode, data: T) -> tide::Result {
+    respond(status, Some(data), None, None)
+}
+
+///
+/// A response with a list in an envelope, its length in `meta.count`.
+///
+pub fn list<T: Serialize>(data: Vec<T>) -> tide::Result {
+    let count = data.len();
+    respond(StatusCode::Ok, Some(data), None, Some(count))
+}
+
+///
+/// A response with an error in an envelope.
+///
+pub fn error(status: StatusCode, message: impl Into<String>) -> tide::Result {
+    let error = ApiError {
+        status: status.into(),
+        message: message.into(),
+    };
+    respond::<()>(status, None, Some(error), None)
+}
+
+///
+/// An error for a bad parameter, which becomes a 400.
+///
+pub fn bad_request(message: impl Into<String>) -> tide::Error {
+    tide::Error::from_str(StatusCode::BadRequest, message.into())
+}
+
+///
+/// Middleware (with `tide::utils::After`) that turns errors of handlers and middleware, which tide
+/// sends as plain text, into envelopes.
+///
+pub async fn envelope_errors(response: Response) -> tide::Result {
+    match response.error() {
+        Some(e) => {
+            let status = response.status();
+            let mut enveloped = error(status, e.to_string())?;
+            for (name, values) in response.iter() {
+                if name.as_str().eq_ignore_ascii_case("www-authenticate") {
+                    enveloped.insert_header(name, values);
+                }
+            }
+            Ok(enveloped)
+        }
+        None => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use tide::http::{self, Method, Url};
+
+    #[test]
+    fn test_OPENAPI() {
+        let spec: serde_json::Value = serde_json::from_str(OPENAPI).unwrap();
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+        for path in [
+            "/tail/{n}",
+            "/symbols",
+            "/indicators/{symbol}",
+            "/openapi.json",
+        ] {
+            assert!(spec["paths"][path].is_object(), "{} isn't documented", path);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_envelope_errors() {
+        let mut app = tide::new();
+        app.with(tide::utils::After(envelope_errors));
+        app.at("numbers/:n")
+            .get(|req: tide::Request<()>| async move {
+                let n: usize = req
+                    .param("n")?
+                    .parse()
+                    .map_err(|_| bad_request("n isn't a number"))?;
+                list(vec![n])
+            });
+
+        let get = |path: &str| {
+            let req = http::Request::new(
+                Method::Get,
+                Url::parse("http://localhost").unwrap().join(path).unwrap(),
+            );
+            let app = app.clone();
+            async move {
+                let mut res: http::Response = app.respond(req).await.unwrap();
+                let body: serde_json::Value = res.body_json().await.unwrap();
+                (res.status(), body)
+            }
+        };
+        let (status, body) = get("/numbers/7").await;
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body["data"], serde_json::json!([7]));
+        assert_eq!(body["meta"]["count"], 1);
+        assert!(body["error"].is_null());
+
+        let (status, body) = get("/numbers/seven").await;
+        assert_eq!(status, StatusCode::BadRequest);
+        assert!(body["data"].is_null());
+        assert_eq!(body["error"]["status"], 400);
+        assert_eq!(body["error"]["message"], "n isn't a number");
+    }
+}
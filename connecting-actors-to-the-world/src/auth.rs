@@ -0,0 +1,253 @@
+use std::{fs, io, path::Path};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tide::{http::Method, Middleware, Next, Request, Response, StatusCode};
+
+///
+/// What a token may do. Admin tokens can do everything read-only ones can.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Query data
+    Read,
+    /// Also change the watchlist and the scheduler
+    Admin,
+}
+
+///
+/// A bearer token of an API client
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct Token {
+    /// Who the token belongs to, for the logs
+    pub name: String,
+    pub token: String,
+    pub scope: Scope,
+}
+
+///
+/// The tokens accepted by the API, usually loaded from a token file
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tokens {
+    pub tokens: Vec<Token>,
+}
+
+impl Tokens {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let tokens: Tokens =
+            toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(token) = tokens.tokens.iter().find(|t| t.token.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the token of '{}' is empty", token.name),
+            ));
+        }
+        Ok(tokens)
+    }
+
+    ///
+    /// Find a token, comparing in constant time so the response time doesn't give it away.
+    ///
+    pub fn find(&self, token: &str) -> Option<&Token> {
+        self.tokens.iter().find(|t| {
+            t.token.len() == token.len()
+                && t.token
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+    }
+}
+
+///
+/// Paths that don't need a token, so orchestrators can probe the process and clients can get the
+/// specification and the dashboard, which asks for a token itself
+///
+const PUBLIC_PATHS: [&str; 4] = ["/", "/healthz", "/readyz", "/openapi.json"];
+
+///
+/// Middleware that requires an `Authorization: Bearer <token>` header: `GET` requests need a read
+/// token, everything else an admin token. The scope of the token is stored in the request's
+/// extensions. Rejected requests are logged.
+///
+#[derive(Debug, Clone)]
+pub struct TokenAuth {
+    pub tokens: Tokens,
+}
+
+impl TokenAuth {
+    ///
+    /// The scope of the request's token if it's allowed, otherwise the status and reason to reject
+    /// it with.
+    ///
+    fn check(
+        &self,
+        authorization: Option<&str>,
+        required: Scope,
+    ) -> Result<Scope, (StatusCode, String)> {
+        // the scheme is case-insensitive (RFC 9110), the token isn't
+        let bearer = authorization
+            .and_then(|a| a.trim_start().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token);
+        let Some(bearer) = bearer else {
+            return Err((StatusCode::Unauthorized, "no bearer token".to_string()));
+        };
+        match self.tokens.find(bearer.trim()) {
+            Some(token) if token.scope >= required => Ok(token.scope),
+            Some(token) => Err((
+                StatusCode::Forbidden,
+                format!("the token of '{}' is read-only", token.name),
+            )),
+            None => Err((StatusCode::Unauthorized, "unknown token".to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TokenAuth {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if PUBLIC_PATHS.contains(&req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+        let required = match req.method() {
+            Method::Get | Method::Head => Scope::Read,
+            _ => Scope::Admin,
+        };
+        let authorization = req.header("Authorization").map(|h| h.last().as_str());
+        match self.check(authorization, required) {
+            Ok(scope) => {
+                req.set_ext(scope);
+                Ok(next.run(req).await)
+            }
+            Err((status, reason)) => {
+                eprintln!(
+                    "Rejected {} {} from {}: {}",
+                    req.method(),
+                    req.url().path(),
+                    req.peer_addr().unwrap_or("an unknown address"),
+                    reason
+                );
+                let mut response = Response::new(status);
+                if status == StatusCode::Unauthorized {
+                    response.insert_header("WWW-Authenticate", "Bearer");
+                    response.set_error(tide::Error::from_str(status, "a valid token is required"));
+                } else {
+                    response.set_error(tide::Error::from_str(status, "this needs an admin token"));
+                }
+                Ok(response)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use tide::http::{self, Url};
+
+    #[async_std::test]
+    async fn test_TokenAuth() {
+        let tokens: Tokens = toml::from_str(
+            r#"
+            [[tokens]]
+            name = "dashboard"
+            token = "read-secret"
+            scope = "read"
+
+            [[tokens]]
+            name = "ops"
+            token = "admin-secret"
+            scope = "admin"
+            "#,
+        )
+        .unwrap();
+        let mut app = tide::new();
+        app.with(TokenAuth { tokens });
+        app.at("healthz").get(|_| async { Ok("ok") });
+        app.at("symbols")
+            .get(|req: Request<()>| async move { Ok(format!("{:?}", req.ext::<Scope>())) })
+            .post(|_| async { Ok("added") });
+
+        let respond = |method, path: &str, token: Option<&str>| {
+            let mut req = http::Request::new(
+                method,
+                Url::parse("http://localhost").unwrap().join(path).unwrap(),
+            );
+            if let Some(token) = token {
+                req.insert_header("Authorization", format!("Bearer {}", token));
+            }
+            let app = app.clone();
+            async move {
+                let mut res: http::Response = app.respond(req).await.unwrap();
+                (res.status(), res.body_string().await.unwrap())
+            }
+        };
+        assert_eq!(
+            respond(Method::Get, "/healthz", None).await,
+            (StatusCode::Ok, "ok".to_string())
+        );
+        assert_eq!(
+            respond(Method::Get, "/symbols", None).await.0,
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            respond(Method::Get, "/symbols", Some("guess")).await.0,
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            respond(Method::Get, "/symbols", Some("read-secret")).await,
+            (StatusCode::Ok, "Some(Read)".to_string())
+        );
+        assert_eq!(
+            respond(Method::Post, "/symbols", Some("read-secret"))
+                .await
+                .0,
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            respond(Method::Post, "/symbols", Some("admin-secret")).await,
+            (StatusCode::Ok, "added".to_string())
+        );
+    }
+
+    #[test]
+    fn test_TokenAuth_check() {
+        let tokens: Tokens = toml::from_str(
+            r#"
+            [[tokens]]
+            name = "dashboard"
+            token = "read-secret"
+            scope = "read"
+            "#,
+        )
+        .unwrap();
+        let auth = TokenAuth { tokens };
+        for authorization in [
+            "Bearer read-secret",
+            "bearer read-secret",
+            "BEARER  read-secret",
+        ] {
+            assert_eq!(
+                auth.check(Some(authorization), Scope::Read),
+                Ok(Scope::Read)
+            );
+        }
+        for authorization in [
+            "Basic read-secret",
+            "Bearerread-secret",
+            "Bearer READ-SECRET",
+        ] {
+            assert_eq!(
+                auth.check(Some(authorization), Scope::Read).unwrap_err().0,
+                StatusCode::Unauthorized
+            );
+        }
+    }
+}
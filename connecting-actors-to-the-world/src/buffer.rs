@@ -0,0 +1,616 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    mem,
+    time::Duration,
+};
+
+use async_std::channel::Sender;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::Serialize;
+use xactor::*;
+
+use crate::{output::parse_size, PerformanceIndicators};
+
+/// How often records are checked for their age
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// How many records the `BufferSink` keeps: at most `per_symbol` of each symbol, none older than
+/// `max_age` (since they were received) and, across all symbols, no more than `max_bytes`. When
+/// the memory budget is exceeded, the oldest records of any symbol are evicted first.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retention {
+    pub per_symbol: usize,
+    pub max_age: Option<Duration>,
+    /// Estimated memory of the records, see `BufferSink::size`
+    pub max_bytes: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            per_symbol: 50,
+            max_age: None,
+            max_bytes: 16 << 20,
+        }
+    }
+}
+
+///
+/// Parse a maximum age in seconds, minutes, hours or days, e.g. `90s`, `30m`, `12h` or `7d`.
+///
+pub fn parse_age(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.len() - s.chars().last().map_or(0, char::len_utf8));
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => 0,
+    };
+    match number.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+        Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("'{}' is not an age like 90s, 30m, 12h or 7d", s)),
+    }
+}
+
+///
+/// Parse a memory budget like `512K` or `16M`.
+///
+pub fn parse_memory(s: &str) -> std::result::Result<usize, String> {
+    match parse_size(s) {
+        Some(size) if size > 0 => Ok(size as usize),
+        _ => Err(format!("'{}' is not a size like 16M", s)),
+    }
+}
+
+///
+/// A stream of new records, see `StreamRequest`
+///
+#[derive(Debug)]
+struct Subscriber {
+    symbols: Vec<String>,
+    sender: Sender<(u64, PerformanceIndicators)>,
+}
+
+#[derive(Debug)]
+struct Record {
+    seq: u64,
+    received: DateTime<Utc>,
+    indicators: PerformanceIndicators,
+}
+
+///
+/// Records evicted since the start, by the limit of `Retention` that evicted them
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Evictions {
+    pub per_symbol: u64,
+    pub max_age: u64,
+    pub max_bytes: u64,
+}
+
+///
+/// The retention policy of the `BufferSink` and what it currently holds
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionStats {
+    pub per_symbol: usize,
+    pub max_age_secs: Option<u64>,
+    pub max_bytes: usize,
+    pub records: usize,
+    /// Estimated memory of the records
+    pub bytes: usize,
+    /// Records of each symbol
+    pub symbols: BTreeMap<String, usize>,
+    pub evicted: Evictions,
+}
+
+///
+/// Actor that keeps the most recent indicators in memory for the API and streams new ones. Each
+/// symbol's records have their own queue, so queries about a symbol only look at its records, and
+/// how many are kept is up to the `Retention` policy.
+///
+/// Records are numbered in the order they arrive. Numbers start at the time the buffer was created
+/// (in microseconds), so they keep increasing across restarts.
+///
+#[derive(Debug, Default)]
+pub struct BufferSink {
+    pub retention: Retention,
+    /// The records of each symbol, oldest first
+    symbols: HashMap<String, VecDeque<Record>>,
+    /// Estimated memory of all records
+    bytes: usize,
+    evicted: Evictions,
+    next: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+impl BufferSink {
+    pub fn new(retention: Retention) -> Self {
+        BufferSink {
+            retention,
+            next: Utc::now().timestamp_micros().max(0) as u64,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Estimated memory a record takes up in the buffer.
+    ///
+    pub fn size(indicators: &PerformanceIndicators) -> usize {
+        mem::size_of::<Record>() + indicators.symbol.len()
+    }
+
+    fn insert(&mut self, msg: PerformanceIndicators) {
+        let seq = self.next;
+        self.next += 1;
+        // a subscriber that's closed or too far behind is dropped, which ends its stream
+        self.subscribers.retain(|s| {
+            let wants = s.symbols.is_empty() || s.symbols.contains(&msg.symbol);
+            !s.sender.is_closed() && (!wants || s.sender.try_send((seq, msg.clone())).is_ok())
+        });
+        let symbol = msg.symbol.clone();
+        self.bytes += Self::size(&msg);
+        let records = self.symbols.entry(symbol.clone()).or_default();
+        records.push_back(Record {
+            seq,
+            received: Utc::now(),
+            indicators: msg,
+        });
+        let excess = records.len().saturating_sub(self.retention.per_symbol);
+        for _ in 0..excess {
+            self.evict(&symbol);
+            self.evicted.per_symbol += 1;
+        }
+        while self.bytes > self.retention.max_bytes {
+            let oldest = self
+                .symbols
+                .iter()
+                .filter_map(|(symbol, records)| Some((records.front()?.seq, symbol)))
+                .min();
+            let Some((_, symbol)) = oldest else {
+                break;
+            };
+            let symbol = symbol.clone();
+            self.evict(&symbol);
+            self.evicted.max_bytes += 1;
+        }
+    }
+
+    ///
+    /// Drop the oldest record of a symbol.
+    ///
+    fn evict(&mut self, symbol: &str) {
+        if let Some(records) = self.symbols.get_mut(symbol) {
+            if let Some(record) = records.pop_front() {
+                self.bytes -= Self::size(&record.indicators);
+            }
+            if records.is_empty() {
+                self.symbols.remove(symbol);
+            }
+        }
+    }
+
+    ///
+    /// Drop the records received longer than `max_age` before `now`.
+    ///
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let Some(max_age) = self
+            .retention
+            .max_age
+            .and_then(|age| chrono::Duration::from_std(age).ok())
+        else {
+            return;
+        };
+        let expired: Vec<(String, usize)> = self
+            .symbols
+            .iter()
+            .map(|(symbol, records)| {
+                let n = records.partition_point(|r| now - r.received > max_age);
+                (symbol.clone(), n)
+            })
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        for (symbol, n) in expired {
+            for _ in 0..n {
+                self.evict(&symbol);
+            }
+            self.evicted.max_age += n as u64;
+        }
+    }
+
+    fn stats(&self) -> RetentionStats {
+        let symbols: BTreeMap<String, usize> = self
+            .symbols
+            .iter()
+            .map(|(symbol, records)| (symbol.clone(), records.len()))
+            .collect();
+        RetentionStats {
+            per_symbol: self.retention.per_symbol,
+            max_age_secs: self.retention.max_age.map(|age| age.as_secs()),
+            max_bytes: self.retention.max_bytes,
+            records: symbols.values().sum(),
+            bytes: self.bytes,
+            symbols,
+            evicted: self.evicted.clone(),
+        }
+    }
+
+    ///
+    /// The latest `n` records of `symbols` (all if empty), newest first.
+    ///
+    fn tail(&self, n: usize, symbols: &[String]) -> Vec<PerformanceIndicators> {
+        let mut records: Vec<&Record> = if symbols.is_empty() {
+            self.symbols
+                .values()
+                .flat_map(|r| r.iter().rev().take(n))
+                .collect()
+        } else {
+            symbols
+                .iter()
+                .filter_map(|s| self.symbols.get(s))
+                .flat_map(|r| r.iter().rev().take(n))
+                .collect()
+        };
+        records.sort_unstable_by_key(|r| Reverse(r.seq));
+        records
+            .into_iter()
+            .take(n)
+            .map(|r| r.indicators.clone())
+            .collect()
+    }
+
+    ///
+    /// The records of `symbols` (all if empty) after the one numbered `after`, oldest first.
+    ///
+    fn after(&self, after: u64, symbols: &[String]) -> Vec<(u64, PerformanceIndicators)> {
+        let queues: Vec<_> = if symbols.is_empty() {
+            self.symbols.values().collect()
+        } else {
+            symbols.iter().filter_map(|s| self.symbols.get(s)).collect()
+        };
+        let mut records: Vec<(u64, PerformanceIndicators)> = queues
+            .into_iter()
+            .flat_map(|r| r.range(r.partition_point(|r| r.seq <= after)..))
+            .map(|r| (r.seq, r.indicators.clone()))
+            .collect();
+        records.sort_unstable_by_key(|(seq, _)| *seq);
+        records
+    }
+
+    fn latest(&self, symbol: &str) -> Option<PerformanceIndicators> {
+        self.symbols
+            .get(symbol)?
+            .back()
+            .map(|r| r.indicators.clone())
+    }
+
+    ///
+    /// The records of a symbol with a timestamp between `from` and `to` (inclusive), newest first.
+    ///
+    fn history(&self, msg: &HistoryRequest) -> Vec<PerformanceIndicators> {
+        self.symbols
+            .get(&msg.symbol)
+            .into_iter()
+            .flat_map(|r| r.iter().rev())
+            .map(|r| &r.indicators)
+            .filter(|i| msg.from.is_none_or(|from| i.timestamp >= from))
+            .filter(|i| msg.to.is_none_or(|to| i.timestamp <= to))
+            .take(msg.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Service for BufferSink {}
+
+#[async_trait]
+impl Actor for BufferSink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        if self.retention.max_age.is_some() {
+            ctx.send_interval(Expire, EXPIRE_INTERVAL);
+        }
+        ctx.subscribe::<PerformanceIndicators>().await
+    }
+}
+
+#[async_trait]
+impl Handler<PerformanceIndicators> for BufferSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        self.insert(msg);
+    }
+}
+
+#[message]
+#[derive(Debug, Clone)]
+struct Expire;
+
+#[async_trait]
+impl Handler<Expire> for BufferSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Expire) {
+        self.expire(Utc::now());
+    }
+}
+
+///
+/// Get the retention policy and statistics of the buffer
+///
+#[derive(Default, Debug)]
+#[message(result = "RetentionStats")]
+pub struct RetentionRequest;
+
+#[async_trait]
+impl Handler<RetentionRequest> for BufferSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: RetentionRequest) -> RetentionStats {
+        self.stats()
+    }
+}
+
+///
+/// Get the latest `n` records of `symbols`, or of all symbols if empty, newest first
+///
+#[derive(Default, Debug)]
+#[message(result = "Vec<PerformanceIndicators>")]
+pub struct BufferDataRequest {
+    pub n: usize,
+    pub symbols: Vec<String>,
+}
+
+#[async_trait]
+impl Handler<BufferDataRequest> for BufferSink {
+    async fn handle(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        msg: BufferDataRequest,
+    ) -> Vec<PerformanceIndicators> {
+        self.tail(msg.n, &msg.symbols)
+    }
+}
+
+///
+/// Get the latest record of a symbol
+///
+#[derive(Default, Debug)]
+#[message(result = "Option<PerformanceIndicators>")]
+pub struct LatestIndicatorsRequest(pub String);
+
+#[async_trait]
+impl Handler<LatestIndicatorsRequest> for BufferSink {
+    async fn handle(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        msg: LatestIndicatorsRequest,
+    ) -> Option<PerformanceIndicators> {
+        self.latest(&msg.0)
+    }
+}
+
+///
+/// Get the records of a symbol in a time range, newest first and at most `limit` of them
+///
+#[derive(Default, Debug)]
+#[message(result = "Vec<PerformanceIndicators>")]
+pub struct HistoryRequest {
+    pub symbol: String,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+#[async_trait]
+impl Handler<HistoryRequest> for BufferSink {
+    async fn handle(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        msg: HistoryRequest,
+    ) -> Vec<PerformanceIndicators> {
+        self.history(&msg)
+    }
+}
+
+///
+/// Stream the new records of `symbols` (all if empty) to `sender`, with their numbers. The result
+/// are the buffered records after the one numbered `after`, so a stream can resume without gaps.
+///
+/// The stream ends when the receiver is dropped, or when its queue is full.
+///
+#[derive(Debug)]
+#[message(result = "Vec<(u64, PerformanceIndicators)>")]
+pub struct StreamRequest {
+    pub after: Option<u64>,
+    pub symbols: Vec<String>,
+    pub sender: Sender<(u64, PerformanceIndicators)>,
+}
+
+#[async_trait]
+impl Handler<StreamRequest> for BufferSink {
+    async fn handle(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        msg: StreamRequest,
+    ) -> Vec<(u64, PerformanceIndicators)> {
+        let history = match msg.after {
+            Some(after) => self.after(after, &msg.symbols),
+            None => vec![],
+        };
+        self.subscribers.push(Subscriber {
+            symbols: msg.symbols,
+            sender: msg.sender,
+        });
+        history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::signal::{Numeric, Price};
+
+    fn indicators(symbol: &str, timestamp: i64) -> PerformanceIndicators {
+        PerformanceIndicators {
+            symbol: symbol.to_string(),
+            timestamp: Utc.timestamp_opt(timestamp, 0).unwrap(),
+            price: Price::one(),
+            pct_change: Price::zero(),
+            period_min: Price::one(),
+            period_max: Price::one(),
+            last_sma: Price::one(),
+        }
+    }
+
+    fn keys(records: &[PerformanceIndicators]) -> Vec<(&str, i64)> {
+        records
+            .iter()
+            .map(|i| (i.symbol.as_str(), i.timestamp.timestamp()))
+            .collect()
+    }
+
+    #[test]
+    fn test_BufferSink_queries() {
+        // room for 5 records
+        let mut buffer = BufferSink::new(Retention {
+            max_bytes: 5 * BufferSink::size(&indicators("AAPL", 0)),
+            ..Default::default()
+        });
+        for (symbol, timestamp) in [("AAPL", 1), ("MSFT", 1), ("AAPL", 2), ("UBER", 1)] {
+            buffer.insert(indicators(symbol, timestamp));
+        }
+        buffer.insert(indicators("AAPL", 3));
+        buffer.insert(indicators("MSFT", 2));
+
+        // the first AAPL record was evicted
+        assert_eq!(
+            keys(&buffer.tail(10, &[])),
+            vec![
+                ("MSFT", 2),
+                ("AAPL", 3),
+                ("UBER", 1),
+                ("AAPL", 2),
+                ("MSFT", 1)
+            ]
+        );
+        assert_eq!(
+            keys(&buffer.tail(3, &["AAPL".to_string(), "MSFT".to_string()])),
+            vec![("MSFT", 2), ("AAPL", 3), ("AAPL", 2)]
+        );
+        assert_eq!(buffer.latest("AAPL").unwrap().timestamp.timestamp(), 3);
+        assert!(buffer.latest("GOOG").is_none());
+
+        let history = buffer.history(&HistoryRequest {
+            symbol: "AAPL".to_string(),
+            to: Some(Utc.timestamp_opt(2, 0).unwrap()),
+            ..Default::default()
+        });
+        assert_eq!(keys(&history), vec![("AAPL", 2)]);
+        let history = buffer.history(&HistoryRequest {
+            symbol: "MSFT".to_string(),
+            limit: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(keys(&history), vec![("MSFT", 2)]);
+    }
+
+    #[test]
+    fn test_BufferSink_retention() {
+        let mut buffer = BufferSink::new(Retention {
+            per_symbol: 2,
+            max_age: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        for timestamp in 1..=5 {
+            buffer.insert(indicators("NOISY", timestamp));
+        }
+        buffer.insert(indicators("QUIET", 1));
+
+        // the noisy symbol doesn't push out the quiet one
+        assert_eq!(
+            keys(&buffer.tail(10, &[])),
+            vec![("QUIET", 1), ("NOISY", 5), ("NOISY", 4)]
+        );
+        let stats = buffer.stats();
+        assert_eq!(stats.records, 3);
+        assert_eq!(stats.bytes, 3 * BufferSink::size(&indicators("NOISY", 0)));
+        assert_eq!(stats.symbols["NOISY"], 2);
+        assert_eq!(stats.evicted.per_symbol, 3);
+
+        buffer.expire(Utc::now());
+        assert_eq!(buffer.stats().records, 3);
+        buffer.expire(Utc::now() + chrono::Duration::minutes(2));
+        let stats = buffer.stats();
+        assert_eq!((stats.records, stats.bytes), (0, 0));
+        assert!(stats.symbols.is_empty());
+        assert_eq!(stats.evicted.max_age, 3);
+        assert!(buffer.latest("QUIET").is_none());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_age("7d"), Ok(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert!(parse_age("0h").is_err());
+        assert!(parse_age("12").is_err());
+        assert!(parse_age("5µ").is_err());
+        assert_eq!(parse_memory("16M"), Ok(16 << 20));
+        assert!(parse_memory("lots").is_err());
+    }
+
+    #[async_std::test]
+    async fn test_BufferSink_stream() {
+        let buffer = BufferSink::new(Retention::default()).start().await.unwrap();
+        let (sender, receiver) = async_std::channel::bounded(2);
+        let history = buffer
+            .call(StreamRequest {
+                after: None,
+                symbols: vec!["AAPL".to_string()],
+                sender,
+            })
+            .await
+            .unwrap();
+        assert!(history.is_empty());
+
+        for (symbol, timestamp) in [("AAPL", 1), ("MSFT", 1), ("AAPL", 2)] {
+            buffer.send(indicators(symbol, timestamp)).unwrap();
+        }
+        let (first, _) = receiver.recv().await.unwrap();
+        let (second, record) = receiver.recv().await.unwrap();
+        assert_eq!(second, first + 2);
+        assert_eq!(record.timestamp.timestamp(), 2);
+
+        // resume after the first, the MSFT record in between is filtered
+        let (sender, _receiver) = async_std::channel::bounded(2);
+        let history = buffer
+            .call(StreamRequest {
+                after: Some(first),
+                symbols: vec!["AAPL".to_string()],
+                sender,
+            })
+            .await
+            .unwrap();
+        let seqs: Vec<u64> = history.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, vec![second]);
+    }
+
+    #[test]
+    fn test_BufferSink_closed_subscribers() {
+        let mut buffer = BufferSink::new(Retention::default());
+        let (sender, receiver) = async_std::channel::bounded(1);
+        buffer.subscribers.push(Subscriber {
+            symbols: vec!["TYPO".to_string()],
+            sender,
+        });
+        buffer.insert(indicators("AAPL", 1));
+        assert_eq!(buffer.subscribers.len(), 1);
+
+        // gone even though its symbol never comes up
+        drop(receiver);
+        buffer.insert(indicators("AAPL", 2));
+        assert!(buffer.subscribers.is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use chrono::{prelude::*, Duration};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+///
+/// Regular trading hours, holidays and early closes of an exchange. Times are local to the
+/// exchange's time zone.
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct Exchange {
+    pub name: String,
+    pub timezone: Tz,
+    pub open: NaiveTime,
+    pub close: NaiveTime,
+    #[serde(default)]
+    pub holidays: Vec<NaiveDate>,
+    #[serde(default)]
+    pub early_closes: HashMap<NaiveDate, NaiveTime>,
+    /// Yahoo symbol suffixes of this exchange, e.g. `.DE`
+    #[serde(default)]
+    pub suffixes: Vec<String>,
+    /// Symbols listed on this exchange
+    #[serde(default)]
+    pub symbols: Vec<String>,
+}
+
+impl Exchange {
+    ///
+    /// The `(open, close)` of the trading session on a local date.
+    ///
+    /// # Returns
+    ///
+    /// The session or `None` on weekends and holidays.
+    ///
+    pub fn session(&self, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if matches!(date.weekday(), Weekday::Sat | Weekday::Sun) || self.holidays.contains(&date) {
+            return None;
+        }
+        let close = self.early_closes.get(&date).unwrap_or(&self.close);
+        let to_utc = |time: &NaiveTime| {
+            self.timezone
+                .from_local_datetime(&date.and_time(*time))
+                .earliest()
+                .map(|t| t.with_timezone(&Utc))
+        };
+        Some((to_utc(&self.open)?, to_utc(close)?))
+    }
+
+    pub fn is_open(&self, at: DateTime<Utc>) -> bool {
+        let date = at.with_timezone(&self.timezone).date_naive();
+        self.session(date)
+            .is_some_and(|(open, close)| open <= at && at < close)
+    }
+
+    ///
+    /// The close of the most recent session that ended at or before `at`, looking back two weeks.
+    ///
+    pub fn last_close(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let today = at.with_timezone(&self.timezone).date_naive();
+        (0..14)
+            .filter_map(|days| self.session(today - Duration::days(days)))
+            .map(|(_, close)| close)
+            .find(|close| *close <= at)
+    }
+}
+
+///
+/// The exchanges of a watchlist, usually loaded from a calendar file
+///
+#[derive(Debug, Clone, Deserialize)]
+pub struct Calendar {
+    /// Name of the exchange for symbols no other exchange claims
+    pub default: String,
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Calendar {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    ///
+    /// Find the exchange of a symbol: explicit listings first, then suffixes, then the default.
+    ///
+    pub fn exchange(&self, symbol: &str) -> Option<&Exchange> {
+        self.exchanges
+            .iter()
+            .find(|e| e.symbols.iter().any(|s| s == symbol))
+            .or_else(|| {
+                self.exchanges
+                    .iter()
+                    .find(|e| e.suffixes.iter().any(|s| symbol.ends_with(s.as_str())))
+            })
+            .or_else(|| self.exchanges.iter().find(|e| e.name == self.default))
+    }
+}
+
+///
+/// Decides which symbols to request quotes for: only while their market is open and, optionally,
+/// once more after it closed. Without a calendar every symbol is always due.
+///
+#[derive(Debug, Default)]
+pub struct MarketSchedule {
+    calendar: Option<Calendar>,
+    post_close_refresh: bool,
+    last_requests: HashMap<String, DateTime<Utc>>,
+}
+
+impl MarketSchedule {
+    pub fn new(calendar: Option<Calendar>, post_close_refresh: bool) -> Self {
+        MarketSchedule {
+            calendar,
+            post_close_refresh,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Check if a symbol's quotes should be requested at `now`, and remember it if so.
+    ///
+    pub fn is_due(&mut self, symbol: &str, now: DateTime<Utc>) -> bool {
+        let due = match self.calendar.as_ref().and_then(|c| c.exchange(symbol)) {
+            None => true,
+            Some(exchange) if exchange.is_open(now) => true,
+            Some(exchange) => {
+                self.post_close_refresh
+                    && exchange.last_close(now).is_some_and(|close| {
+                        self.last_requests
+                            .get(symbol)
+                            .is_none_or(|last| *last < close)
+                    })
+            }
+        };
+        if due {
+            self.last_requests.insert(symbol.to_string(), now);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn calendar() -> Calendar {
+        toml::from_str(
+            r#"
+            default = "NYSE"
+
+            [[exchanges]]
+            name = "NYSE"
+            timezone = "America/New_York"
+            open = "09:30:00"
+            close = "16:00:00"
+            holidays = ["2026-11-26"]
+            early_closes = { "2026-11-27" = "13:00:00" }
+
+            [[exchanges]]
+            name = "XETRA"
+            timezone = "Europe/Berlin"
+            open = "09:00:00"
+            close = "17:30:00"
+            suffixes = [".DE"]
+            symbols = ["SAP"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_Exchange_session() {
+        let calendar = calendar();
+        let nyse = calendar.exchange("AAPL").unwrap();
+        assert_eq!(nyse.name, "NYSE");
+
+        // summer and winter time
+        assert_eq!(
+            nyse.session(NaiveDate::from_ymd_opt(2026, 7, 1).unwrap()),
+            Some((utc("2026-07-01T13:30:00Z"), utc("2026-07-01T20:00:00Z")))
+        );
+        assert_eq!(
+            nyse.session(NaiveDate::from_ymd_opt(2026, 12, 1).unwrap()),
+            Some((utc("2026-12-01T14:30:00Z"), utc("2026-12-01T21:00:00Z")))
+        );
+        // weekend, holiday and early close
+        assert_eq!(
+            nyse.session(NaiveDate::from_ymd_opt(2026, 10, 17).unwrap()),
+            None
+        );
+        assert_eq!(
+            nyse.session(NaiveDate::from_ymd_opt(2026, 11, 26).unwrap()),
+            None
+        );
+        assert_eq!(
+            nyse.session(NaiveDate::from_ymd_opt(2026, 11, 27).unwrap()),
+            Some((utc("2026-11-27T14:30:00Z"), utc("2026-11-27T18:00:00Z")))
+        );
+
+        assert!(nyse.is_open(utc("2026-10-19T15:00:00Z")));
+        assert!(!nyse.is_open(utc("2026-10-19T20:00:00Z")));
+        assert_eq!(
+            nyse.last_close(utc("2026-10-19T12:00:00Z")),
+            Some(utc("2026-10-16T20:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_Calendar_exchange() {
+        let calendar = calendar();
+        assert_eq!(calendar.exchange("SAP").unwrap().name, "XETRA");
+        assert_eq!(calendar.exchange("BMW.DE").unwrap().name, "XETRA");
+        assert_eq!(calendar.exchange("MSFT").unwrap().name, "NYSE");
+
+        let calendar = Calendar::from_file("calendar.toml").unwrap();
+        assert_eq!(calendar.exchange("AAPL").unwrap().name, "NASDAQ");
+        assert_eq!(calendar.exchange("SIE.DE").unwrap().name, "XETRA");
+    }
+
+    #[test]
+    fn test_MarketSchedule_is_due() {
+        let mut schedule = MarketSchedule::new(Some(calendar()), false);
+        assert!(schedule.is_due("AAPL", utc("2026-10-19T15:00:00Z")));
+        assert!(!schedule.is_due("AAPL", utc("2026-10-19T21:00:00Z")));
+        assert!(!schedule.is_due("SAP", utc("2026-10-19T21:00:00Z")));
+
+        let mut schedule = MarketSchedule::new(Some(calendar()), true);
+        assert!(schedule.is_due("AAPL", utc("2026-10-19T15:00:00Z")));
+        // one refresh after the close, then nothing until the next session
+        assert!(schedule.is_due("AAPL", utc("2026-10-19T20:00:30Z")));
+        assert!(!schedule.is_due("AAPL", utc("2026-10-19T20:01:00Z")));
+        assert!(!schedule.is_due("AAPL", utc("2026-10-20T08:00:00Z")));
+        assert!(schedule.is_due("AAPL", utc("2026-10-20T13:30:00Z")));
+
+        let mut schedule = MarketSchedule::default();
+        assert!(schedule.is_due("AAPL", utc("2026-10-18T12:00:00Z")));
+    }
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(feature = "decimal")]
+use arrow_array::Decimal128Array;
+use arrow_array::{
+    ArrayRef, Float64Array, RecordBatch, StringArray, TimestampSecondArray, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use xactor::*;
+use yahoo_finance_api as yahoo;
+
+use crate::{
+    health::{Health, Restart},
+    shutdown::Drain,
+    signal::Price,
+    Flush, PerformanceIndicators, Quotes,
+};
+
+/// The symbol and (UTC) date of the rows in a file
+type Partition = (String, NaiveDate);
+
+#[cfg(feature = "decimal")]
+const PRECISION: u8 = 38;
+#[cfg(feature = "decimal")]
+const SCALE: i8 = 18;
+
+/// Indicators are stored exactly with the `decimal` feature, rounded to `SCALE` decimals
+#[cfg(feature = "decimal")]
+const PRICE_TYPE: DataType = DataType::Decimal128(PRECISION, SCALE);
+#[cfg(not(feature = "decimal"))]
+const PRICE_TYPE: DataType = DataType::Float64;
+
+fn timestamp_field() -> Field {
+    Field::new(
+        "timestamp",
+        DataType::Timestamp(TimeUnit::Second, Some("UTC".into())),
+        false,
+    )
+}
+
+fn quotes_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("symbol", DataType::Utf8, false),
+        timestamp_field(),
+        Field::new("open", DataType::Float64, false),
+        Field::new("high", DataType::Float64, false),
+        Field::new("low", DataType::Float64, false),
+        Field::new("close", DataType::Float64, false),
+        Field::new("adjclose", DataType::Float64, false),
+        Field::new("volume", DataType::UInt64, false),
+    ])
+}
+
+fn indicators_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("symbol", DataType::Utf8, false),
+        timestamp_field(),
+        Field::new("price", PRICE_TYPE, false),
+        Field::new("pct_change", PRICE_TYPE, false),
+        Field::new("period_min", PRICE_TYPE, false),
+        Field::new("period_max", PRICE_TYPE, false),
+        Field::new("last_sma", PRICE_TYPE, false),
+    ])
+}
+
+fn quotes_batch(symbol: &str, rows: &[yahoo::Quote]) -> Result<RecordBatch> {
+    let floats = |f: fn(&yahoo::Quote) -> f64| -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(rows.iter().map(f)))
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(vec![symbol; rows.len()])),
+        Arc::new(
+            TimestampSecondArray::from_iter_values(rows.iter().map(|q| q.timestamp as i64))
+                .with_timezone("UTC"),
+        ),
+        floats(|q| q.open),
+        floats(|q| q.high),
+        floats(|q| q.low),
+        floats(|q| q.close),
+        floats(|q| q.adjclose),
+        Arc::new(UInt64Array::from_iter_values(rows.iter().map(|q| q.volume))),
+    ];
+    Ok(RecordBatch::try_new(Arc::new(quotes_schema()), columns)?)
+}
+
+#[cfg(not(feature = "decimal"))]
+fn prices(
+    rows: &[PerformanceIndicators],
+    f: fn(&PerformanceIndicators) -> Price,
+) -> Result<ArrayRef> {
+    Ok(Arc::new(Float64Array::from_iter_values(rows.iter().map(f))))
+}
+
+///
+/// Values with more than `SCALE` decimals are rounded, ones too large for the column are an error.
+///
+#[cfg(feature = "decimal")]
+fn prices(
+    rows: &[PerformanceIndicators],
+    f: fn(&PerformanceIndicators) -> Price,
+) -> Result<ArrayRef> {
+    let values = rows
+        .iter()
+        .map(|row| {
+            let value = f(row).round_dp(SCALE as u32);
+            value
+                .mantissa()
+                .checked_mul(10i128.pow(SCALE as u32 - value.scale()))
+                .ok_or_else(|| Error::msg(format!("{} doesn't fit a Decimal128", value)))
+        })
+        .collect::<Result<Vec<i128>>>()?;
+    let array = Decimal128Array::from(values).with_precision_and_scale(PRECISION, SCALE)?;
+    array.validate_decimal_precision(PRECISION)?;
+    Ok(Arc::new(array))
+}
+
+fn indicators_batch(symbol: &str, rows: &[PerformanceIndicators]) -> Result<RecordBatch> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(vec![symbol; rows.len()])),
+        Arc::new(
+            TimestampSecondArray::from_iter_values(rows.iter().map(|i| i.timestamp.timestamp()))
+                .with_timezone("UTC"),
+        ),
+        prices(rows, |i| i.price)?,
+        prices(rows, |i| i.pct_change)?,
+        prices(rows, |i| i.period_min)?,
+        prices(rows, |i| i.period_max)?,
+        prices(rows, |i| i.last_sma)?,
+    ];
+    Ok(RecordBatch::try_new(
+        Arc::new(indicators_schema()),
+        columns,
+    )?)
+}
+
+fn write_batch(path: &Path, batch: &RecordBatch) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), Some(properties))?;
+    // a partial file would duplicate its rows once they are written again
+    if let Err(e) = writer
+        .write(batch)
+        .and_then(|()| writer.close().map(|_| ()))
+    {
+        let _ = fs::remove_file(path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+///
+/// Buffers quotes and indicators and writes them as Parquet files, partitioned Hive-style by symbol
+/// and date: `<dir>/quotes/symbol=AAPL/date=2024-01-31/part-<n>.parquet`. Parquet files can't be
+/// appended to, so each flush adds new part files.
+///
+/// Quotes are re-downloaded with every request, only the ones newer than the last seen quote of a
+/// symbol are kept. The same goes for indicators.
+///
+#[derive(Debug, Default)]
+pub struct ParquetWriter {
+    pub dir: PathBuf,
+    /// Flush a partition once it buffered this many rows
+    pub rows_per_file: usize,
+    quotes: BTreeMap<Partition, Vec<yahoo::Quote>>,
+    indicators: BTreeMap<Partition, Vec<PerformanceIndicators>>,
+    last_quote: HashMap<String, u64>,
+    last_indicators: HashMap<String, DateTime<Utc>>,
+    files: usize,
+}
+
+impl ParquetWriter {
+    pub fn new(dir: impl Into<PathBuf>, rows_per_file: usize) -> Self {
+        ParquetWriter {
+            dir: dir.into(),
+            rows_per_file,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_quotes(&mut self, msg: &Quotes) -> Result<()> {
+        let last = self.last_quote.get(&msg.symbol).copied();
+        for quote in msg.quotes.iter().filter(|q| last < Some(q.timestamp)) {
+            let timestamp = Utc.timestamp_opt(quote.timestamp as i64, 0).unwrap();
+            let partition = (msg.symbol.clone(), timestamp.date_naive());
+            self.quotes
+                .entry(partition)
+                .or_default()
+                .push(quote.clone());
+        }
+        if let Some(newest) = msg.quotes.iter().map(|q| q.timestamp).max() {
+            self.last_quote
+                .insert(msg.symbol.clone(), newest.max(last.unwrap_or(0)));
+        }
+        self.flush_full()
+    }
+
+    pub fn add_indicators(&mut self, msg: &PerformanceIndicators) -> Result<()> {
+        if self
+            .last_indicators
+            .get(&msg.symbol)
+            .is_some_and(|last| *last >= msg.timestamp)
+        {
+            return Ok(());
+        }
+        self.last_indicators
+            .insert(msg.symbol.clone(), msg.timestamp);
+        let partition = (msg.symbol.clone(), msg.timestamp.date_naive());
+        self.indicators
+            .entry(partition)
+            .or_default()
+            .push(msg.clone());
+        self.flush_full()
+    }
+
+    ///
+    /// Write all buffered rows. If a partition fails to write, it and the ones not written yet stay
+    /// buffered for the next flush.
+    ///
+    pub fn flush(&mut self) -> Result<()> {
+        let mut quotes = std::mem::take(&mut self.quotes).into_iter();
+        while let Some(((symbol, date), rows)) = quotes.next() {
+            let path = self.next_path("quotes", &symbol, date);
+            if let Err(e) = quotes_batch(&symbol, &rows).and_then(|b| write_batch(&path, &b)) {
+                self.quotes.insert((symbol, date), rows);
+                self.quotes.extend(quotes);
+                return Err(e);
+            }
+        }
+        let mut indicators = std::mem::take(&mut self.indicators).into_iter();
+        while let Some(((symbol, date), rows)) = indicators.next() {
+            let path = self.next_path("indicators", &symbol, date);
+            if let Err(e) = indicators_batch(&symbol, &rows).and_then(|b| write_batch(&path, &b)) {
+                self.indicators.insert((symbol, date), rows);
+                self.indicators.extend(indicators);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_full(&mut self) -> Result<()> {
+        let full = |rows: usize| rows >= self.rows_per_file.max(1);
+        if self.quotes.values().any(|r| full(r.len()))
+            || self.indicators.values().any(|r| full(r.len()))
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn next_path(&mut self, table: &str, symbol: &str, date: NaiveDate) -> PathBuf {
+        self.files += 1;
+        self.dir
+            .join(table)
+            .join(format!("symbol={}", symbol))
+            .join(format!("date={}", date))
+            .join(format!(
+                "part-{}-{}.parquet",
+                Utc::now().timestamp_millis(),
+                self.files
+            ))
+    }
+}
+
+///
+/// Actor for storing incoming quotes and indicators as Parquet files. Rows are written once a
+/// partition is full and every `flush_interval`, so they don't wait in memory for long. If the
+/// files fail to write, it's restarted with a backoff and drops messages until then, like
+/// `FileSink`.
+///
+#[derive(Debug, Default)]
+pub struct ParquetSink {
+    pub writer: ParquetWriter,
+    /// Write the buffered rows this often, never if zero
+    pub flush_interval: Duration,
+    pub health: Health,
+}
+
+impl ParquetSink {
+    ///
+    /// Create the directory, or restart later if that fails.
+    ///
+    async fn start(&mut self, ctx: &mut Context<Self>) {
+        match fs::create_dir_all(&self.writer.dir) {
+            Ok(()) => self.health.up().await,
+            Err(e) => {
+                let error = format!("couldn't create '{}': {}", self.writer.dir.display(), e);
+                self.health.failed(ctx, error).await
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush().map_err(|e| {
+            Error::msg(format!(
+                "couldn't write Parquet files to '{}': {}",
+                self.writer.dir.display(),
+                e
+            ))
+        })
+    }
+
+    ///
+    /// Write what's buffered, data is lost if that fails.
+    ///
+    fn close(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Couldn't flush, data was lost: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Actor for ParquetSink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        if !self.flush_interval.is_zero() {
+            ctx.send_interval(Flush, self.flush_interval);
+        }
+        ctx.subscribe::<Quotes>().await?;
+        ctx.subscribe::<PerformanceIndicators>().await?;
+        self.start(ctx).await;
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Restart> for ParquetSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Restart) {
+        self.start(ctx).await;
+    }
+}
+
+#[async_trait]
+impl Handler<Drain> for ParquetSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Flush> for ParquetSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Flush) {
+        if !self.health.is_up() {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            self.health.failed(ctx, e.to_string()).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<Quotes> for ParquetSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: Quotes) {
+        if !self.health.is_up() {
+            return;
+        }
+        if let Err(e) = self.writer.add_quotes(&msg) {
+            let error = format!("couldn't write quotes of '{}': {}", msg.symbol, e);
+            self.health.failed(ctx, error).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<PerformanceIndicators> for ParquetSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        if !self.health.is_up() {
+            return;
+        }
+        if let Err(e) = self.writer.add_indicators(&msg) {
+            let error = format!("couldn't write indicators of '{}': {}", msg.symbol, e);
+            self.health.failed(ctx, error).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::signal::Numeric;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn parquet_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(parquet_files(&path));
+            } else {
+                files.push(path);
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_ParquetWriter() {
+        let dir = std::env::temp_dir().join(format!("parquet-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut writer = ParquetWriter::new(&dir, 1000);
+
+        let quote = |timestamp| yahoo::Quote {
+            timestamp,
+            open: 1.0,
+            high: 2.0,
+            low: 0.5,
+            volume: 100,
+            close: 1.5,
+            adjclose: 1.5,
+        };
+        // two days, the second request repeats the first day
+        let quotes = Quotes {
+            symbol: "AAPL".to_string(),
+            quotes: vec![quote(1_700_000_000)],
+            dividends: vec![],
+        };
+        writer.add_quotes(&quotes).unwrap();
+        let quotes = Quotes {
+            symbol: "AAPL".to_string(),
+            quotes: vec![quote(1_700_000_000), quote(1_700_086_400)],
+            dividends: vec![],
+        };
+        writer.add_quotes(&quotes).unwrap();
+        let indicators = PerformanceIndicators {
+            symbol: "AAPL".to_string(),
+            timestamp: Utc.timestamp_opt(1_700_086_400, 0).unwrap(),
+            price: Price::one(),
+            pct_change: Price::zero(),
+            period_min: Price::one(),
+            period_max: Price::one(),
+            last_sma: Price::one(),
+        };
+        writer.add_indicators(&indicators).unwrap();
+        writer.add_indicators(&indicators).unwrap();
+        assert!(!dir.exists());
+        writer.flush().unwrap();
+
+        let files = parquet_files(&dir);
+        let partitions: Vec<_> = files
+            .iter()
+            .map(|f| f.parent().unwrap().strip_prefix(&dir).unwrap())
+            .collect();
+        assert_eq!(
+            partitions,
+            vec![
+                Path::new("indicators/symbol=AAPL/date=2023-11-15"),
+                Path::new("quotes/symbol=AAPL/date=2023-11-14"),
+                Path::new("quotes/symbol=AAPL/date=2023-11-15"),
+            ]
+        );
+        for file in &files {
+            let reader = SerializedFileReader::new(File::open(file).unwrap()).unwrap();
+            assert_eq!(reader.metadata().file_metadata().num_rows(), 1);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ParquetWriter_flush_failed() {
+        let dir = std::env::temp_dir().join(format!("parquet-failed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut writer = ParquetWriter::new(&dir, 1000);
+        for symbol in ["AAA", "BBB"] {
+            let quotes = Quotes {
+                symbol: symbol.to_string(),
+                quotes: vec![yahoo::Quote {
+                    timestamp: 1_700_000_000,
+                    open: 1.0,
+                    high: 2.0,
+                    low: 0.5,
+                    volume: 100,
+                    close: 1.5,
+                    adjclose: 1.5,
+                }],
+                dividends: vec![],
+            };
+            writer.add_quotes(&quotes).unwrap();
+        }
+        let indicators = PerformanceIndicators {
+            symbol: "AAA".to_string(),
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            price: Price::one(),
+            pct_change: Price::zero(),
+            period_min: Price::one(),
+            period_max: Price::one(),
+            last_sma: Price::one(),
+        };
+        writer.add_indicators(&indicators).unwrap();
+
+        // a file where the first partition's directory goes
+        fs::create_dir_all(dir.join("quotes")).unwrap();
+        let blocking = dir.join("quotes").join("symbol=AAA");
+        File::create(&blocking).unwrap();
+        assert!(writer.flush().is_err());
+        assert!(!dir.join("indicators").exists());
+
+        // nothing was dropped, the next flush writes all of it
+        fs::remove_file(&blocking).unwrap();
+        writer.flush().unwrap();
+        let files: Vec<_> = parquet_files(&dir)
+            .iter()
+            .map(|f| {
+                f.parent()
+                    .unwrap()
+                    .strip_prefix(&dir)
+                    .unwrap()
+                    .to_path_buf()
+            })
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                Path::new("indicators/symbol=AAA/date=2023-11-14"),
+                Path::new("quotes/symbol=AAA/date=2023-11-14"),
+                Path::new("quotes/symbol=BBB/date=2023-11-14"),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_indicators_batch_decimal() {
+        use arrow_array::Array;
+
+        let third = Price::one() / Price::from_usize(3);
+        let indicators = PerformanceIndicators {
+            symbol: "AAPL".to_string(),
+            timestamp: Utc.timestamp_opt(0, 0).unwrap(),
+            price: Price::from_f64(0.1).unwrap(),
+            pct_change: third,
+            period_min: Price::one(),
+            period_max: Price::one(),
+            last_sma: Price::one(),
+        };
+        let batch = indicators_batch("AAPL", std::slice::from_ref(&indicators)).unwrap();
+        let price = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .unwrap();
+        assert_eq!(price.data_type(), &DataType::Decimal128(38, 18));
+        assert_eq!(price.value_as_string(0), "0.100000000000000000");
+        let pct_change = batch
+            .column(3)
+            .as_any()
+            .downcast_ref::<Decimal128Array>()
+            .unwrap();
+        assert_eq!(pct_change.value_as_string(0), "0.333333333333333333");
+
+        let too_large = PerformanceIndicators {
+            price: Price::MAX,
+            ..indicators
+        };
+        assert!(indicators_batch("AAPL", &[too_large]).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_ParquetSink_flush() {
+        let dir = std::env::temp_dir().join(format!("parquet-flush-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let sink = ParquetSink {
+            writer: ParquetWriter::new(&dir, 1000),
+            flush_interval: Duration::from_millis(20),
+            ..Default::default()
+        }
+        .start()
+        .await
+        .unwrap();
+        let quotes = Quotes {
+            symbol: "PQFL".to_string(),
+            quotes: vec![yahoo::Quote {
+                timestamp: 1_700_000_000,
+                open: 1.0,
+                high: 2.0,
+                low: 0.5,
+                volume: 100,
+                close: 1.5,
+                adjclose: 1.5,
+            }],
+            dividends: vec![],
+        };
+        sink.send(quotes).unwrap();
+
+        // written by the timer, without a drain or a full partition
+        async_std::task::sleep(Duration::from_millis(200)).await;
+        let files = parquet_files(&dir.join("quotes").join("symbol=PQFL"));
+        assert_eq!(files.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use yahoo_finance_api as yahoo;
+
+use crate::{
+    signal::{Numeric, Price},
+    Interval, PerformanceIndicators,
+};
+
+///
+/// Column names of the raw format, the same as the fields of `PerformanceIndicators`
+///
+pub const COLUMNS: [&str; 7] = [
+    "timestamp",
+    "symbol",
+    "price",
+    "pct_change",
+    "period_min",
+    "period_max",
+    "last_sma",
+];
+
+///
+/// Column names of raw quotes in the raw format
+///
+pub const QUOTE_COLUMNS: [&str; 8] = [
+    "timestamp",
+    "symbol",
+    "open",
+    "high",
+    "low",
+    "close",
+    "adjclose",
+    "volume",
+];
+
+///
+/// Parse a CSV delimiter from a single ASCII character, or `\t`/`tab` for tabs.
+///
+pub fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() && s != "\"" => Ok(s.as_bytes()[0]),
+        _ => Err(format!("'{}' is not a single ASCII character", s)),
+    }
+}
+
+///
+/// How `PerformanceIndicators` and quotes are written as CSV. Fields are quoted as in RFC 4180
+/// when needed. Numbers are written raw unless `pretty` is set, which decorates them with `$` and
+/// `%` and rounds them to 2 decimals by default.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct CsvFormat {
+    pub delimiter: u8,
+    /// Number of decimals, all of them if `None`. Not applied to a raw `pct_change`
+    pub precision: Option<usize>,
+    pub pretty: bool,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        CsvFormat {
+            delimiter: b',',
+            precision: None,
+            pretty: false,
+        }
+    }
+}
+
+impl CsvFormat {
+    pub fn writer<W: Write>(&self, w: W) -> csv::Writer<W> {
+        csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .from_writer(w)
+    }
+
+    pub fn header(&self, interval: Interval) -> Vec<String> {
+        if self.pretty {
+            vec![
+                "period start".to_string(),
+                "symbol".to_string(),
+                "price".to_string(),
+                "change %".to_string(),
+                "min".to_string(),
+                "max".to_string(),
+                interval.sma_header(30),
+            ]
+        } else {
+            COLUMNS.iter().map(|c| c.to_string()).collect()
+        }
+    }
+
+    pub fn record(&self, indicators: &PerformanceIndicators) -> Vec<String> {
+        let percent = indicators.pct_change * Price::from_usize(100);
+        vec![
+            indicators.timestamp.to_rfc3339(),
+            indicators.symbol.clone(),
+            self.price(indicators.price),
+            // a raw fraction isn't rounded, two decimals would already lose most of it
+            if self.pretty {
+                format!("{}%", self.number(percent))
+            } else {
+                indicators.pct_change.to_string()
+            },
+            self.price(indicators.period_min),
+            self.price(indicators.period_max),
+            self.price(indicators.last_sma),
+        ]
+    }
+
+    pub fn quote_header(&self) -> Vec<String> {
+        if self.pretty {
+            [
+                "period start",
+                "symbol",
+                "open",
+                "high",
+                "low",
+                "close",
+                "adj close",
+                "volume",
+            ]
+            .iter()
+            .map(|c| c.to_string())
+            .collect()
+        } else {
+            QUOTE_COLUMNS.iter().map(|c| c.to_string()).collect()
+        }
+    }
+
+    ///
+    /// A quote as a record. Prices that can't be represented (e.g. `NaN` with the `decimal`
+    /// feature) are left empty rather than written as a wrong number.
+    ///
+    pub fn quote_record(&self, symbol: &str, quote: &yahoo::Quote) -> Vec<String> {
+        let price = |value: f64| match Price::from_f64(value) {
+            Some(price) => self.price(price),
+            None => {
+                eprintln!(
+                    "Leaving out the price {} of '{}' at {}, it can't be represented",
+                    value, symbol, quote.timestamp
+                );
+                String::new()
+            }
+        };
+        let timestamp =
+            chrono::DateTime::from_timestamp(quote.timestamp as i64, 0).unwrap_or_default();
+        vec![
+            timestamp.to_rfc3339(),
+            symbol.to_string(),
+            price(quote.open),
+            price(quote.high),
+            price(quote.low),
+            price(quote.close),
+            price(quote.adjclose),
+            quote.volume.to_string(),
+        ]
+    }
+
+    ///
+    /// A single record as a line of text, e.g. for stdout.
+    ///
+    pub fn line(&self, record: &[String]) -> String {
+        let mut writer = self.writer(vec![]);
+        let _ = writer.write_record(record);
+        let line = writer.into_inner().unwrap_or_default();
+        let line = String::from_utf8_lossy(&line);
+        // only the terminator, trailing whitespace in the last field is data
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        line.strip_suffix('\r').unwrap_or(line).to_string()
+    }
+
+    fn price(&self, value: Price) -> String {
+        if self.pretty {
+            format!("${}", self.number(value))
+        } else {
+            self.number(value)
+        }
+    }
+
+    fn number(&self, value: Price) -> String {
+        match (self.precision, self.pretty) {
+            (Some(precision), _) => format!("{:.*}", precision, round(value, precision)),
+            (None, true) => format!("{:.2}", round(value, 2)),
+            (None, false) => value.to_string(),
+        }
+    }
+}
+
+///
+/// Records written as CSV one at a time while they are read, e.g. for a streamed HTTP body, so the
+/// whole file is never in memory.
+///
+pub struct CsvReader<I> {
+    format: CsvFormat,
+    records: I,
+    line: Vec<u8>,
+    position: usize,
+}
+
+impl<I: Iterator<Item = Vec<String>>> CsvReader<I> {
+    pub fn new(format: CsvFormat, records: impl IntoIterator<IntoIter = I>) -> Self {
+        CsvReader {
+            format,
+            records: records.into_iter(),
+            line: vec![],
+            position: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<String>> + Unpin> async_std::io::Read for CsvReader<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.position == this.line.len() {
+            let Some(record) = this.records.next() else {
+                return Poll::Ready(Ok(0));
+            };
+            this.line.clear();
+            this.position = 0;
+            let mut writer = this.format.writer(&mut this.line);
+            writer.write_record(&record)?;
+            writer.flush()?;
+        }
+        let n = buf.len().min(this.line.len() - this.position);
+        buf[..n].copy_from_slice(&this.line[this.position..this.position + n]);
+        this.position += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+///
+/// `Decimal` truncates when formatted with a precision, so it's rounded (half to even) like `f64`.
+///
+#[cfg(feature = "decimal")]
+fn round(value: Price, decimals: usize) -> Price {
+    value.round_dp(decimals as u32)
+}
+
+#[cfg(not(feature = "decimal"))]
+fn round(value: Price, _decimals: usize) -> Price {
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use chrono::prelude::*;
+
+    fn indicators(symbol: &str) -> PerformanceIndicators {
+        PerformanceIndicators {
+            symbol: symbol.to_string(),
+            timestamp: Utc.timestamp_opt(0, 0).unwrap(),
+            price: Price::from_f64(1.75).unwrap(),
+            pct_change: Price::from_f64(0.125).unwrap(),
+            period_min: Price::one(),
+            period_max: Price::from_usize(2),
+            last_sma: Price::from_f64(1.5).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_CsvFormat_line() {
+        let raw = CsvFormat::default();
+        assert_eq!(
+            raw.line(&raw.header(Interval::OneDay)),
+            "timestamp,symbol,price,pct_change,period_min,period_max,last_sma"
+        );
+        assert_eq!(
+            raw.line(&raw.record(&indicators("AAPL"))),
+            "1970-01-01T00:00:00+00:00,AAPL,1.75,0.125,1,2,1.5"
+        );
+
+        let pretty = CsvFormat {
+            pretty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pretty.line(&pretty.header(Interval::FiveMinutes)),
+            "period start,symbol,price,change %,min,max,30x5m avg"
+        );
+        assert_eq!(
+            pretty.line(&pretty.record(&indicators("AAPL"))),
+            "1970-01-01T00:00:00+00:00,AAPL,$1.75,12.50%,$1.00,$2.00,$1.50"
+        );
+
+        let semicolons = CsvFormat {
+            delimiter: b';',
+            precision: Some(1),
+            pretty: false,
+        };
+        assert_eq!(
+            semicolons.line(&semicolons.record(&indicators("A;\"B\""))),
+            "1970-01-01T00:00:00+00:00;\"A;\"\"B\"\"\";1.8;0.125;1.0;2.0;1.5"
+        );
+        let pretty = CsvFormat {
+            precision: Some(1),
+            pretty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pretty.line(&pretty.record(&indicators("AAPL"))),
+            "1970-01-01T00:00:00+00:00,AAPL,$1.8,12.5%,$1.0,$2.0,$1.5"
+        );
+        assert_eq!(
+            raw.line(&["AAPL".to_string(), "trailing ".to_string()]),
+            "AAPL,trailing "
+        );
+    }
+
+    #[test]
+    fn test_CsvFormat_quote_record() {
+        let quote = yahoo::Quote {
+            timestamp: 86_400,
+            open: 1.0,
+            high: 2.5,
+            low: 0.5,
+            volume: 1200,
+            close: 1.5,
+            adjclose: 1.25,
+        };
+        let raw = CsvFormat::default();
+        assert_eq!(
+            raw.line(&raw.quote_header()),
+            "timestamp,symbol,open,high,low,close,adjclose,volume"
+        );
+        assert_eq!(
+            raw.line(&raw.quote_record("AAPL", &quote)),
+            "1970-01-02T00:00:00+00:00,AAPL,1,2.5,0.5,1.5,1.25,1200"
+        );
+        let pretty = CsvFormat {
+            pretty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pretty.line(&pretty.quote_record("AAPL", &quote)),
+            "1970-01-02T00:00:00+00:00,AAPL,$1.00,$2.50,$0.50,$1.50,$1.25,1200"
+        );
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_CsvFormat_quote_record_unrepresentable() {
+        let quote = yahoo::Quote {
+            timestamp: 86_400,
+            open: 1.0,
+            high: 2.5,
+            low: 0.5,
+            volume: 1200,
+            close: f64::NAN,
+            adjclose: 1.25,
+        };
+        let raw = CsvFormat::default();
+        assert_eq!(
+            raw.line(&raw.quote_record("AAPL", &quote)),
+            "1970-01-02T00:00:00+00:00,AAPL,1,2.5,0.5,,1.25,1200"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_CsvReader() {
+        use async_std::io::ReadExt;
+
+        let format = CsvFormat::default();
+        let records = [indicators("AAPL"), indicators("A,B")];
+        let mut reader = CsvReader::new(
+            format,
+            std::iter::once(format.header(Interval::OneDay))
+                .chain(records.iter().map(|i| format.record(i))),
+        );
+        // reads smaller than a line continue where the last one stopped
+        let mut start = [0; 4];
+        reader.read_exact(&mut start).await.unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&start) + rest.as_str(),
+            "timestamp,symbol,price,pct_change,period_min,period_max,last_sma\n\
+             1970-01-01T00:00:00+00:00,AAPL,1.75,0.125,1,2,1.5\n\
+             1970-01-01T00:00:00+00:00,\"A,B\",1.75,0.125,1,2,1.5\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(parse_delimiter(";"), Ok(b';'));
+        assert_eq!(parse_delimiter("tab"), Ok(b'\t'));
+        assert!(parse_delimiter("ab").is_err());
+        assert!(parse_delimiter("\"").is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::Serialize;
+use xactor::*;
+
+///
+/// The state of a sink
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    /// Failed and waiting for a restart, messages are dropped meanwhile
+    Degraded,
+    /// Out of restarts, messages are dropped from now on
+    Failed,
+}
+
+///
+/// Health event of a sink, published whenever it starts or fails
+///
+#[message]
+#[derive(Debug, Clone, Serialize)]
+pub struct SinkHealth {
+    pub sink: String,
+    pub status: Status,
+    /// Restarts since the sink was created
+    pub restarts: usize,
+    pub error: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+///
+/// When to restart a failed sink: the delay doubles with every consecutive failure, up to
+/// `max_delay`, and after `max_restarts` of them the sink gives up.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max_delay: Duration,
+    pub max_restarts: usize,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_restarts: 5,
+        }
+    }
+}
+
+impl Backoff {
+    ///
+    /// The delay before the next restart after `failures` consecutive ones, `None` if there are
+    /// no restarts left.
+    ///
+    pub fn delay(&self, failures: usize) -> Option<Duration> {
+        if failures >= self.max_restarts {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(failures as u32);
+        Some(self.initial.saturating_mul(factor).min(self.max_delay))
+    }
+}
+
+///
+/// Restart a failed sink
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct Restart;
+
+///
+/// Keeps track of a sink's health, schedules its restarts and publishes `SinkHealth` events.
+///
+#[derive(Debug, Default)]
+pub struct Health {
+    pub sink: String,
+    pub backoff: Backoff,
+    status: Option<Status>,
+    /// Failures since the sink was last up
+    failures: usize,
+    restarts: usize,
+}
+
+impl Health {
+    pub fn new(sink: &str, backoff: Backoff) -> Self {
+        Health {
+            sink: sink.to_string(),
+            backoff,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.status == Some(Status::Up)
+    }
+
+    ///
+    /// The sink (re)started successfully.
+    ///
+    pub async fn up(&mut self) {
+        self.failures = 0;
+        if !self.is_up() {
+            self.publish(Status::Up, None).await;
+        }
+    }
+
+    ///
+    /// The sink failed: send it a `Restart` after the backoff delay, or give up.
+    ///
+    pub async fn failed<A: Handler<Restart>>(&mut self, ctx: &mut Context<A>, error: String) {
+        match self.backoff.delay(self.failures) {
+            Some(delay) => {
+                eprintln!(
+                    "{} failed, restarting in {}s: {}",
+                    self.sink,
+                    delay.as_secs_f64(),
+                    error
+                );
+                self.failures += 1;
+                self.restarts += 1;
+                ctx.send_later(Restart, delay);
+                self.publish(Status::Degraded, Some(error)).await;
+            }
+            None => {
+                eprintln!("{} failed too often, giving up: {}", self.sink, error);
+                self.publish(Status::Failed, Some(error)).await;
+            }
+        }
+    }
+
+    async fn publish(&mut self, status: Status, error: Option<String>) {
+        self.status = Some(status);
+        let health = SinkHealth {
+            sink: self.sink.clone(),
+            status,
+            restarts: self.restarts,
+            error,
+            timestamp: Utc::now(),
+        };
+        match Broker::from_registry().await {
+            Ok(mut broker) => {
+                if let Err(e) = broker.publish(health) {
+                    eprintln!("{}", e);
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+///
+/// The result of a download, published by the downloader after every request
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct Fetched {
+    pub symbol: String,
+    pub timestamp: DateTime<Utc>,
+    pub quotes: usize,
+    pub error: Option<String>,
+}
+
+///
+/// A supervisor had to start an actor again
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct ActorRestarted(pub &'static str);
+
+///
+/// Start an actor like `Supervisor::start`, and publish an `ActorRestarted` whenever the
+/// supervisor creates it again.
+///
+pub async fn supervise<A, F>(name: &'static str, f: F) -> Result<Addr<A>>
+where
+    A: Actor,
+    F: Fn() -> A + Send + 'static,
+{
+    let created = AtomicBool::new(false);
+    Supervisor::start(move || {
+        if created.swap(true, Ordering::Relaxed) {
+            async_std::task::spawn(async move {
+                match Broker::from_registry().await {
+                    Ok(mut broker) => {
+                        if let Err(e) = broker.publish(ActorRestarted(name)) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            });
+        }
+        f()
+    })
+    .await
+}
+
+///
+/// The downloads of a symbol
+///
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SymbolStatus {
+    pub last_fetch: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// The error of the latest failed download, also after later ones succeeded
+    pub last_error: Option<String>,
+    pub failures: usize,
+    /// Quotes downloaded since the start
+    pub quotes: usize,
+}
+
+///
+/// Everything the `HealthMonitor` knows
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusReport {
+    pub started: DateTime<Utc>,
+    pub uptime_secs: i64,
+    pub symbols: BTreeMap<String, SymbolStatus>,
+    pub sinks: Vec<SinkHealth>,
+    /// Supervisor restarts by actor, only those that were restarted
+    pub restarts: BTreeMap<String, usize>,
+}
+
+impl StatusReport {
+    ///
+    /// Why the pipeline isn't ready, empty if it is: all sinks have to be up, and a download has to
+    /// have succeeded.
+    ///
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = self
+            .sinks
+            .iter()
+            .filter(|h| h.status != Status::Up)
+            .map(|h| format!("{} is {}", h.sink, format!("{:?}", h.status).to_lowercase()))
+            .collect();
+        if self.symbols.values().all(|s| s.last_success.is_none()) {
+            problems.push("no successful download yet".to_string());
+        }
+        problems
+    }
+}
+
+///
+/// Actor that keeps the latest health event of each sink, the downloads of each symbol and the
+/// actor restarts.
+///
+#[derive(Debug)]
+pub struct HealthMonitor {
+    /// When the process started, for the uptime
+    started: DateTime<Utc>,
+    sinks: BTreeMap<String, SinkHealth>,
+    symbols: BTreeMap<String, SymbolStatus>,
+    restarts: BTreeMap<String, usize>,
+}
+
+impl HealthMonitor {
+    pub fn new(started: DateTime<Utc>) -> Self {
+        HealthMonitor {
+            started,
+            sinks: BTreeMap::new(),
+            symbols: BTreeMap::new(),
+            restarts: BTreeMap::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Actor for HealthMonitor {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<SinkHealth>().await?;
+        ctx.subscribe::<Fetched>().await?;
+        ctx.subscribe::<ActorRestarted>().await
+    }
+}
+
+#[async_trait]
+impl Handler<Fetched> for HealthMonitor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Fetched) {
+        let status = self.symbols.entry(msg.symbol).or_default();
+        status.last_fetch = Some(msg.timestamp);
+        status.quotes += msg.quotes;
+        match msg.error {
+            Some(error) => {
+                status.last_error = Some(error);
+                status.failures += 1;
+            }
+            None => status.last_success = Some(msg.timestamp),
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<ActorRestarted> for HealthMonitor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: ActorRestarted) {
+        *self.restarts.entry(msg.0.to_string()).or_default() += 1;
+    }
+}
+
+#[async_trait]
+impl Handler<SinkHealth> for HealthMonitor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: SinkHealth) {
+        self.sinks.insert(msg.sink.clone(), msg);
+    }
+}
+
+///
+/// Get the health of all sinks, sorted by name
+///
+#[derive(Default, Debug)]
+#[message(result = "Vec<SinkHealth>")]
+pub struct HealthRequest;
+
+#[async_trait]
+impl Handler<HealthRequest> for HealthMonitor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: HealthRequest) -> Vec<SinkHealth> {
+        self.sinks.values().cloned().collect()
+    }
+}
+
+///
+/// Get a `StatusReport`
+///
+#[derive(Default, Debug)]
+#[message(result = "StatusReport")]
+pub struct StatusRequest;
+
+#[async_trait]
+impl Handler<StatusRequest> for HealthMonitor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: StatusRequest) -> StatusReport {
+        let now = Utc::now();
+        StatusReport {
+            started: self.started,
+            uptime_secs: (now - self.started).num_seconds(),
+            symbols: self.symbols.clone(),
+            sinks: self.sinks.values().cloned().collect(),
+            restarts: self.restarts.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_Backoff_delay() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            max_restarts: 4,
+        };
+        let delays: Vec<_> = (0..5).map(|n| backoff.delay(n)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                Some(Duration::from_secs(5)),
+                None
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_HealthMonitor_status() {
+        let started = Utc::now() - chrono::Duration::seconds(10);
+        let monitor = HealthMonitor::new(started).start().await.unwrap();
+        let fetched = |symbol: &str, quotes, error: Option<&str>| Fetched {
+            symbol: symbol.to_string(),
+            timestamp: Utc::now(),
+            quotes,
+            error: error.map(str::to_string),
+        };
+        monitor.send(fetched("AAPL", 0, Some("timeout"))).unwrap();
+        monitor
+            .send(SinkHealth {
+                sink: "FileSink".to_string(),
+                status: Status::Degraded,
+                restarts: 1,
+                error: Some("disk full".to_string()),
+                timestamp: Utc::now(),
+            })
+            .unwrap();
+        let report = monitor.call(StatusRequest).await.unwrap();
+        assert_eq!(
+            report.problems(),
+            vec!["FileSink is degraded", "no successful download yet"]
+        );
+        assert!(report.uptime_secs >= 10);
+
+        monitor.send(fetched("AAPL", 30, None)).unwrap();
+        monitor.send(fetched("AAPL", 2, None)).unwrap();
+        monitor.send(ActorRestarted("Scheduler")).unwrap();
+        let report = monitor.call(StatusRequest).await.unwrap();
+        let aapl = &report.symbols["AAPL"];
+        assert_eq!(aapl.quotes, 32);
+        assert_eq!(aapl.failures, 1);
+        assert_eq!(aapl.last_error.as_deref(), Some("timeout"));
+        assert_eq!(aapl.last_success, aapl.last_fetch);
+        assert_eq!(report.restarts["Scheduler"], 1);
+        assert_eq!(report.problems(), vec!["FileSink is degraded"]);
+    }
+}
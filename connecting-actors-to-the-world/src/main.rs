@@ -1,23 +1,69 @@
 use std::{
-    collections::VecDeque,
-    fs::File,
-    io::{BufWriter, Write},
+    io, iter,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use async_std::{prelude::*, stream};
+use async_std::{io::BufReader, prelude::FutureExt, stream::StreamExt};
 use async_trait::async_trait;
 use chrono::prelude::*;
-use clap::Parser;
-use serde::Serialize;
-use tide::{Body, Request, Response, StatusCode};
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use tide::{
+    listener::{Listener, ToListener},
+    Body, Request, Response, Server, StatusCode,
+};
+use tide_rustls::TlsListener;
+use tide_websockets::{Message, WebSocket, WebSocketConnection};
 use xactor::*;
 use yahoo_finance_api as yahoo;
 
+mod api;
+mod auth;
+mod buffer;
+mod calendar;
+mod columnar;
+mod csv_format;
+mod health;
+mod metrics;
+mod output;
+mod price;
+mod scheduler;
+mod shutdown;
 mod signal;
-use signal::{AsyncStockSignal, MaxPrice, MinPrice, PriceDifference, WindowedSMA};
+mod sqlite;
+mod websocket;
+use auth::{Scope, TokenAuth, Tokens};
+use buffer::{
+    parse_age, parse_memory, BufferDataRequest, BufferSink, HistoryRequest,
+    LatestIndicatorsRequest, Retention, RetentionRequest, StreamRequest,
+};
+use calendar::Calendar;
+use columnar::{ParquetSink, ParquetWriter};
+use csv_format::{parse_delimiter, CsvFormat, CsvReader};
+use health::{
+    supervise, Backoff, Fetched, Health, HealthMonitor, HealthRequest, Restart, StatusRequest,
+};
+use metrics::{InfluxSink, InfluxTarget, LatestQuotesRequest, LatestRequest, LatestSink};
+use output::{FlushPolicy, OutputOptions, RotatingFiles, Rotation};
+use price::{price_series, Adjustment, PriceField};
+use scheduler::{
+    read_watchlist, AddSymbol, Pause, RemoveSymbol, Resume, Scheduler, WatchedSymbol, Watchlist,
+};
+use shutdown::Drain;
+use signal::{AsyncStockSignal, MaxPrice, MinPrice, Numeric, Price, PriceDifference, WindowedSMA};
+use sqlite::SqliteSink;
+use websocket::{ClientSession, Received};
 
-const BUFFER_SIZE: usize = 50;
+/// Records an event stream may fall behind before it's closed
+const STREAM_QUEUE: usize = 100;
+/// Updates a WebSocket client may fall behind before it's disconnected
+const CLIENT_QUEUE: usize = 100;
+/// How long the brokers may take to answer a readiness check
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+const PARQUET_ROWS_PER_FILE: usize = 10_000;
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -30,6 +76,189 @@ struct Opts {
     symbols: String,
     #[clap(short, long)]
     from: String,
+    /// The quote field to calculate the signals on, `close` with `--adjust total-return`
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = PriceField::Adjclose,
+        default_value_if("adjust", "total-return", "close")
+    )]
+    price_field: PriceField,
+    /// Adjustment applied to the price series
+    #[clap(long, value_enum, default_value_t = Adjustment::None)]
+    adjust: Adjustment,
+    /// The bar size of the quotes
+    #[clap(long, value_enum, default_value_t = Interval::OneDay)]
+    interval: Interval,
+    /// Exchange calendar file (e.g. calendar.toml) to only request quotes while markets are open
+    #[clap(long)]
+    calendar: Option<String>,
+    /// Request quotes once more after a market closed
+    #[clap(long)]
+    post_close_refresh: bool,
+    /// File to keep the watchlist in; it replaces `--symbols` once it exists
+    #[clap(long)]
+    watchlist: Option<PathBuf>,
+    /// Format of the output file and stdout
+    #[clap(long, value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+    /// Output file, `{timestamp}.csv` (or `.jsonl`) by default. `{date}` is replaced with the
+    /// date, `{symbol}` with the symbol for one file per symbol and `{timestamp}` with the start
+    /// time
+    #[clap(short, long)]
+    output: Option<String>,
+    /// Append to existing output files instead of truncating them
+    #[clap(long)]
+    append: bool,
+    /// Start a new output file `daily` or once it reached a size like `100M`
+    #[clap(long)]
+    rotate: Option<Rotation>,
+    /// Compress rotated output files with gzip
+    #[clap(long)]
+    gzip: bool,
+    /// Flush the output file every `N` records, every `Ns` seconds or only on `shutdown`
+    #[clap(long, default_value = "5s")]
+    flush: FlushPolicy,
+    /// Sync the output file to disk whenever it's flushed
+    #[clap(long)]
+    fsync: bool,
+    /// Times a sink restarts after consecutive failures before it gives up
+    #[clap(long, default_value_t = 5)]
+    sink_restarts: usize,
+    /// SQLite database to also store the indicators in, created if it doesn't exist
+    #[clap(long)]
+    sqlite: Option<PathBuf>,
+    /// Store the raw quotes in the SQLite database too
+    #[clap(long, requires = "sqlite")]
+    sqlite_quotes: bool,
+    /// Directory to also write quotes and indicators to as Parquet files
+    #[clap(long)]
+    parquet: Option<PathBuf>,
+    /// Seconds between writes of the buffered Parquet rows, each adds part files
+    #[clap(long, default_value_t = 60)]
+    parquet_flush: u64,
+    /// Download once, write Parquet files to this directory and exit
+    #[clap(long, conflicts_with = "parquet")]
+    export: Option<PathBuf>,
+    /// Also send the indicators as InfluxDB line protocol to `udp://host:port`, `tcp://host:port`
+    /// or append them to a file
+    #[clap(long)]
+    influx: Option<InfluxTarget>,
+    /// Field delimiter of the CSV output, a single character or `tab`
+    #[clap(long, value_parser = parse_delimiter, default_value = ",")]
+    delimiter: u8,
+    /// Decimals of the numbers in the CSV output, all of them by default. A raw `pct_change` is
+    /// never rounded
+    #[clap(long)]
+    precision: Option<usize>,
+    /// Write `$` prices and `%` changes rounded to 2 decimals instead of raw numbers
+    #[clap(long)]
+    pretty: bool,
+    /// Records of each symbol the API keeps in memory
+    #[clap(long, default_value_t = 50)]
+    buffer_per_symbol: usize,
+    /// Drop the records the API keeps once they were received this long ago, e.g. `30m` or `7d`
+    #[clap(long, value_parser = parse_age)]
+    buffer_max_age: Option<Duration>,
+    /// Memory budget of the records the API keeps, e.g. `16M`; the oldest go first beyond it
+    #[clap(long, value_parser = parse_memory, default_value = "16M")]
+    buffer_memory: usize,
+    /// Address the HTTP API listens on
+    #[clap(long, default_value = "localhost")]
+    listen: String,
+    /// Port of the HTTP API
+    #[clap(long, default_value_t = 4321)]
+    port: u16,
+    /// Token file (e.g. tokens.toml) to require bearer tokens for the HTTP API, which is open to
+    /// anyone otherwise
+    #[clap(long)]
+    tokens: Option<PathBuf>,
+    /// PEM certificate (chain) to serve the HTTP API over TLS
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the TLS certificate
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Seconds to wait for downloads and sinks to finish on Ctrl-C/SIGTERM
+    #[clap(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+}
+
+///
+/// The bar size of the quotes. Signals are calculated over bars, so with intraday bars the moving
+/// average spans minutes or hours instead of days.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Interval {
+    #[value(name = "1m")]
+    OneMinute,
+    #[value(name = "5m")]
+    FiveMinutes,
+    #[value(name = "15m")]
+    FifteenMinutes,
+    #[value(name = "1h")]
+    OneHour,
+    #[default]
+    #[value(name = "1d")]
+    OneDay,
+}
+
+impl Interval {
+    ///
+    /// The interval parameter of the provider's API.
+    ///
+    fn as_str(&self) -> &'static str {
+        match self {
+            Interval::OneMinute => "1m",
+            Interval::FiveMinutes => "5m",
+            Interval::FifteenMinutes => "15m",
+            Interval::OneHour => "1h",
+            Interval::OneDay => "1d",
+        }
+    }
+
+    ///
+    /// How far back the provider serves bars of this size, `None` if there is no limit.
+    ///
+    fn max_history(&self) -> Option<chrono::Duration> {
+        match self {
+            Interval::OneMinute => Some(chrono::Duration::days(7)),
+            Interval::FiveMinutes | Interval::FifteenMinutes => Some(chrono::Duration::days(60)),
+            Interval::OneHour => Some(chrono::Duration::days(730)),
+            Interval::OneDay => None,
+        }
+    }
+
+    ///
+    /// Move `from` forward if it's further back than the provider keeps bars of this size.
+    ///
+    fn clamp_start(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> DateTime<Utc> {
+        match self.max_history() {
+            Some(max) if to - from > max => to - max,
+            _ => from,
+        }
+    }
+
+    ///
+    /// The CSV column header of a moving average over `window` bars, e.g. `30d avg` or `30x5m avg`.
+    ///
+    fn sma_header(&self, window: usize) -> String {
+        match self {
+            Interval::OneDay => format!("{window}d avg"),
+            _ => format!("{window}x{} avg", self.as_str()),
+        }
+    }
+}
+
+///
+/// Format of the performance indicator records
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Csv,
+    /// JSON Lines (NDJSON), one object per record
+    #[value(alias = "ndjson")]
+    Jsonl,
 }
 
 #[message]
@@ -37,6 +266,7 @@ struct Opts {
 struct Quotes {
     pub symbol: String,
     pub quotes: Vec<yahoo::Quote>,
+    pub dividends: Vec<yahoo::Dividend>,
 }
 
 #[message]
@@ -45,6 +275,7 @@ struct QuoteRequest {
     symbol: String,
     from: DateTime<Utc>,
     to: DateTime<Utc>,
+    interval: Interval,
 }
 
 ///
@@ -55,11 +286,11 @@ struct QuoteRequest {
 pub struct PerformanceIndicators {
     pub symbol: String,
     pub timestamp: DateTime<Utc>,
-    pub price: f64,
-    pub pct_change: f64,
-    pub period_min: f64,
-    pub period_max: f64,
-    pub last_sma: f64,
+    pub price: Price,
+    pub pct_change: Price,
+    pub period_min: Price,
+    pub period_max: Price,
+    pub last_sma: Price,
 }
 
 ///
@@ -67,42 +298,74 @@ pub struct PerformanceIndicators {
 ///
 struct StockDataDownloader;
 
-#[async_trait]
-impl Handler<QuoteRequest> for StockDataDownloader {
-    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: QuoteRequest) {
-        let symbol = msg.symbol.clone();
-
-        let start = yahoo::time::OffsetDateTime::from_unix_timestamp(msg.from.timestamp()).unwrap();
-        let end = yahoo::time::OffsetDateTime::from_unix_timestamp(msg.to.timestamp()).unwrap();
-        let provider = yahoo::YahooConnector::new();
-        let data = match provider.get_quote_history(&msg.symbol, start, end).await {
-            Ok(response) => {
-                if let Ok(quotes) = response.quotes() {
-                    Quotes {
-                        symbol: symbol.clone(),
-                        quotes,
-                    }
-                } else {
-                    Quotes {
-                        symbol: symbol.clone(),
-                        quotes: vec![],
-                    }
-                }
+///
+/// Download the quotes of a request. API errors and responses without valid quotes are logged and
+/// result in empty quotes, along with the error.
+///
+async fn fetch_quotes(msg: &QuoteRequest) -> (Quotes, Option<String>) {
+    let symbol = msg.symbol.clone();
+
+    let from = msg.interval.clamp_start(msg.from, msg.to);
+    let start = yahoo::time::OffsetDateTime::from_unix_timestamp(from.timestamp()).unwrap();
+    let end = yahoo::time::OffsetDateTime::from_unix_timestamp(msg.to.timestamp()).unwrap();
+    let provider = yahoo::YahooConnector::new();
+    match provider
+        .get_quote_history_interval(&msg.symbol, start, end, msg.interval.as_str())
+        .await
+    {
+        Ok(response) => match response.quotes() {
+            Ok(quotes) => {
+                let quotes = Quotes {
+                    symbol: symbol.clone(),
+                    quotes,
+                    dividends: response.dividends().unwrap_or_default(),
+                };
+                (quotes, None)
             }
             Err(e) => {
-                eprintln!("Ignoring API error for symbol '{}': {}", symbol, e);
-                Quotes {
+                eprintln!("Ignoring invalid quotes of symbol '{}': {}", symbol, e);
+                let quotes = Quotes {
                     symbol: symbol.clone(),
-                    quotes: vec![],
-                }
+                    ..Default::default()
+                };
+                (quotes, Some(e.to_string()))
             }
+        },
+        Err(e) => {
+            eprintln!("Ignoring API error for symbol '{}': {}", symbol, e);
+            let quotes = Quotes {
+                symbol: symbol.clone(),
+                ..Default::default()
+            };
+            (quotes, Some(e.to_string()))
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<QuoteRequest> for StockDataDownloader {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: QuoteRequest) {
+        let (data, error) = fetch_quotes(&msg).await;
+        let fetched = Fetched {
+            symbol: msg.symbol,
+            timestamp: Utc::now(),
+            quotes: data.quotes.len(),
+            error,
         };
+        if let Err(e) = Broker::from_registry().await.unwrap().publish(fetched) {
+            eprint!("{}", e);
+        }
         if let Err(e) = Broker::from_registry().await.unwrap().publish(data) {
             eprint!("{}", e);
         }
     }
 }
 
+#[async_trait]
+impl Handler<Drain> for StockDataDownloader {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {}
+}
+
 #[async_trait]
 impl Actor for StockDataDownloader {
     async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
@@ -113,63 +376,95 @@ impl Actor for StockDataDownloader {
 ///
 /// Actor to create performance indicators from incoming stock data
 ///
-struct StockDataProcessor;
+#[derive(Clone)]
+struct StockDataProcessor {
+    price_field: PriceField,
+    adjustment: Adjustment,
+    output: OutputFormat,
+    format: CsvFormat,
+}
 
-#[async_trait]
-impl Handler<Quotes> for StockDataProcessor {
-    async fn handle(&mut self, _ctx: &mut Context<Self>, mut msg: Quotes) {
+impl StockDataProcessor {
+    ///
+    /// Calculate the performance indicators of the quotes, `None` if there are none or a price
+    /// can't be represented.
+    ///
+    async fn indicators(&self, msg: &mut Quotes) -> Option<PerformanceIndicators> {
         let data = msg.quotes.as_mut_slice();
-        if !data.is_empty() {
-            // ensure that the data is sorted by time (asc)
-            data.sort_by_cached_key(|k| k.timestamp);
+        if data.is_empty() {
+            return None;
+        }
+        // ensure that the data is sorted by time (asc)
+        data.sort_by_cached_key(|k| k.timestamp);
 
-            let last_date = Utc
-                .timestamp_opt(data.last().unwrap().timestamp as i64, 0)
-                .unwrap();
-            let closes: Vec<f64> = data.iter().map(|q| q.close).collect();
-
-            let diff = PriceDifference {};
-            let min = MinPrice {};
-            let max = MaxPrice {};
-            let sma = WindowedSMA { window_size: 30 };
-
-            let period_max: f64 = max.calculate(&closes).await.unwrap_or(0.0);
-            let period_min: f64 = min.calculate(&closes).await.unwrap_or(0.0);
-
-            let last_price = *closes.last().unwrap();
-            let (_, pct_change) = diff.calculate(&closes).await.unwrap_or((0.0, 0.0));
-            let sma = sma.calculate(&closes).await.unwrap();
-
-            let data = PerformanceIndicators {
-                timestamp: last_date,
-                symbol: msg.symbol.clone(),
-                price: last_price,
-                pct_change,
-                period_min,
-                period_max,
-                last_sma: *sma.last().unwrap_or(&0.0),
-            };
+        let last_date = Utc
+            .timestamp_opt(data.last().unwrap().timestamp as i64, 0)
+            .unwrap();
+        // dropping a price would shift the window against the timestamps
+        let Some(prices) = price_series(data, &msg.dividends, self.price_field, self.adjustment)
+            .into_iter()
+            .map(Numeric::from_f64)
+            .collect::<Option<Vec<Price>>>()
+        else {
+            eprintln!(
+                "Ignoring the quotes of symbol '{}', a price can't be represented",
+                msg.symbol
+            );
+            return None;
+        };
+
+        let diff = PriceDifference {};
+        let min = MinPrice {};
+        let max = MaxPrice {};
+        let sma = WindowedSMA { window_size: 30 };
 
+        let period_max: Price = max.calculate(&prices).await.unwrap_or(Price::zero());
+        let period_min: Price = min.calculate(&prices).await.unwrap_or(Price::zero());
+
+        let last_price = *prices.last().unwrap();
+        let (_, pct_change) = diff
+            .calculate(&prices)
+            .await
+            .unwrap_or((Price::zero(), Price::zero()));
+        let sma = sma.calculate(&prices).await.unwrap();
+
+        Some(PerformanceIndicators {
+            timestamp: last_date,
+            symbol: msg.symbol.clone(),
+            price: last_price,
+            pct_change,
+            period_min,
+            period_max,
+            last_sma: *sma.last().unwrap_or(&Price::zero()),
+        })
+    }
+}
+
+#[async_trait]
+impl Handler<Quotes> for StockDataProcessor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, mut msg: Quotes) {
+        if let Some(data) = self.indicators(&mut msg).await {
+            match self.output {
+                OutputFormat::Csv => println!("{}", self.format.line(&self.format.record(&data))),
+                OutputFormat::Jsonl => match serde_json::to_string(&data) {
+                    Ok(line) => println!("{}", line),
+                    Err(e) => eprintln!("{}", e),
+                },
+            }
             if let Err(e) = Broker::from_registry().await.unwrap().publish(data) {
                 eprint!("{}", e);
             }
-
-            println!(
-                "{},{},${:.2},{:.2}%,${:.2},${:.2},${:.2}",
-                last_date.to_rfc3339(),
-                msg.symbol,
-                last_price,
-                pct_change * 100.0,
-                period_min,
-                period_max,
-                sma.last().unwrap_or(&0.0)
-            );
         } else {
             println!("Got nothing");
         }
     }
 }
 
+#[async_trait]
+impl Handler<Drain> for StockDataProcessor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {}
+}
+
 #[async_trait]
 impl Actor for StockDataProcessor {
     async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
@@ -178,149 +473,1517 @@ impl Actor for StockDataProcessor {
 }
 
 ///
-/// Actor for storing incoming messages in a csv file
+/// Write out a sink's buffered records, sent by its timer. File sinks only have one if their flush
+/// policy has an interval.
+///
+#[message]
+#[derive(Debug, Clone)]
+struct Flush;
+
+///
+/// Start the timer of a flush policy that has one.
+///
+fn flush_timer<A: Handler<Flush>>(ctx: &mut Context<A>, policy: FlushPolicy) {
+    if let FlushPolicy::Interval(interval) = policy {
+        ctx.send_interval(Flush, interval);
+    }
+}
+
+///
+/// Actor for storing incoming messages in csv files. If the files fail to open or write, it's
+/// restarted with a backoff and drops messages until then.
 ///
 #[derive(Default, Debug)]
 pub struct FileSink {
-    pub filename: String,
-    pub writer: Option<BufWriter<File>>,
+    pub files: RotatingFiles,
+    pub interval: Interval,
+    pub format: CsvFormat,
+    pub health: Health,
+}
+
+impl FileSink {
+    fn header(&self) -> String {
+        format!("{}\n", self.format.line(&self.format.header(self.interval)))
+    }
+
+    ///
+    /// Open the files, or restart later if that fails.
+    ///
+    async fn start(&mut self, ctx: &mut Context<Self>) {
+        let header = self.header();
+        match self.files.open(Utc::now(), header.as_bytes()) {
+            Ok(()) => self.health.up().await,
+            Err(e) => {
+                self.health
+                    .failed(ctx, format!("couldn't open {}", e))
+                    .await
+            }
+        }
+    }
+
+    ///
+    /// Flush and close the files.
+    ///
+    fn close(&mut self) {
+        if let Err(e) = self.files.close() {
+            eprintln!("Couldn't flush {}, data was lost", e);
+        }
+    }
 }
 
 #[async_trait]
 impl Actor for FileSink {
     async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
-        let mut file = File::create(&self.filename)
-            .unwrap_or_else(|_| panic!("Could not open target file '{}'", self.filename));
-        let _ = writeln!(
-            &mut file,
-            "period start,symbol,price,change %,min,max,30d avg"
-        );
-        self.writer = Some(BufWriter::new(file));
-        ctx.subscribe::<PerformanceIndicators>().await
+        flush_timer(ctx, self.files.options.flush);
+        ctx.subscribe::<PerformanceIndicators>().await?;
+        self.start(ctx).await;
+        Ok(())
     }
 
-    async fn stopped(&mut self, ctx: &mut Context<Self>) {
-        if let Some(writer) = &mut self.writer {
-            writer
-                .flush()
-                .expect("Something happened when flushing. Data loss :(")
-        };
-        ctx.stop(None);
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Restart> for FileSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Restart) {
+        self.start(ctx).await;
+    }
+}
+
+#[async_trait]
+impl Handler<Drain> for FileSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Flush> for FileSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Flush) {
+        if !self.health.is_up() {
+            return;
+        }
+        if let Err(e) = self.files.flush() {
+            self.health
+                .failed(ctx, format!("couldn't flush {}", e))
+                .await;
+        }
     }
 }
 
 #[async_trait]
 impl Handler<PerformanceIndicators> for FileSink {
-    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: PerformanceIndicators) {
-        if let Some(file) = &mut self.writer {
-            let _ = writeln!(
-                file,
-                "{},{},${:.2},{:.2}%,${:.2},${:.2},${:.2}",
-                msg.timestamp.to_rfc3339(),
-                msg.symbol,
-                msg.price,
-                msg.pct_change * 100.0,
-                msg.period_min,
-                msg.period_max,
-                msg.last_sma
-            );
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        if !self.health.is_up() {
+            return;
+        }
+        let record = format!("{}\n", self.format.line(&self.format.record(&msg)));
+        let header = self.header();
+        if let Err(e) = self.files.write(
+            &msg.symbol,
+            Utc::now(),
+            header.as_bytes(),
+            record.as_bytes(),
+        ) {
+            self.health
+                .failed(ctx, format!("couldn't write to {}", e))
+                .await;
         }
     }
 }
 
+///
+/// Actor for storing incoming messages as JSON Lines, one object per line. Fails and restarts
+/// like `FileSink`.
+///
 #[derive(Default, Debug)]
-struct BufferSink {
-    data_sink: VecDeque<PerformanceIndicators>,
+pub struct JsonLinesSink {
+    pub files: RotatingFiles,
+    pub health: Health,
 }
 
-impl Service for BufferSink {}
+impl JsonLinesSink {
+    ///
+    /// Open the files, or restart later if that fails.
+    ///
+    async fn start(&mut self, ctx: &mut Context<Self>) {
+        match self.files.open(Utc::now(), b"") {
+            Ok(()) => self.health.up().await,
+            Err(e) => {
+                self.health
+                    .failed(ctx, format!("couldn't open {}", e))
+                    .await
+            }
+        }
+    }
+
+    ///
+    /// Flush and close the files.
+    ///
+    fn close(&mut self) {
+        if let Err(e) = self.files.close() {
+            eprintln!("Couldn't flush {}, data was lost", e);
+        }
+    }
+}
 
 #[async_trait]
-impl Actor for BufferSink {
+impl Actor for JsonLinesSink {
     async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
-        ctx.subscribe::<PerformanceIndicators>().await
+        flush_timer(ctx, self.files.options.flush);
+        ctx.subscribe::<PerformanceIndicators>().await?;
+        self.start(ctx).await;
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.close();
     }
 }
 
 #[async_trait]
-impl Handler<PerformanceIndicators> for BufferSink {
-    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: PerformanceIndicators) {
-        self.data_sink.push_front(msg);
-        self.data_sink.truncate(BUFFER_SIZE);
+impl Handler<Restart> for JsonLinesSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Restart) {
+        self.start(ctx).await;
     }
 }
 
-#[derive(Default, Debug)]
-#[message(result = "Vec<PerformanceIndicators>")]
-struct BufferDataRequest(usize);
+#[async_trait]
+impl Handler<Drain> for JsonLinesSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {
+        self.close();
+    }
+}
 
 #[async_trait]
-impl Handler<BufferDataRequest> for BufferSink {
-    async fn handle(
-        &mut self,
-        _ctx: &mut Context<Self>,
-        msg: BufferDataRequest,
-    ) -> Vec<PerformanceIndicators> {
-        self.data_sink.iter().take(msg.0).cloned().collect()
+impl Handler<Flush> for JsonLinesSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Flush) {
+        if !self.health.is_up() {
+            return;
+        }
+        if let Err(e) = self.files.flush() {
+            self.health
+                .failed(ctx, format!("couldn't flush {}", e))
+                .await;
+        }
     }
 }
 
-async fn tail(req: Request<Addr<BufferSink>>) -> tide::Result {
-    let n: usize = req.param("n")?.parse()?;
+#[async_trait]
+impl Handler<PerformanceIndicators> for JsonLinesSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        if !self.health.is_up() {
+            return;
+        }
+        let mut record = match serde_json::to_vec(&msg) {
+            Ok(record) => record,
+            Err(e) => return eprintln!("Couldn't serialize '{}': {}", msg.symbol, e),
+        };
+        record.push(b'\n');
+        if let Err(e) = self.files.write(&msg.symbol, Utc::now(), b"", &record) {
+            self.health
+                .failed(ctx, format!("couldn't write to {}", e))
+                .await;
+        }
+    }
+}
+
+///
+/// State shared by all HTTP handlers
+///
+#[derive(Clone)]
+struct AppState {
+    buffer: Addr<BufferSink>,
+    latest: Addr<LatestSink>,
+    health: Addr<HealthMonitor>,
+    scheduler: Addr<Scheduler>,
+    /// The format of the `FileSink`, for exports
+    format: CsvFormat,
+    interval: Interval,
+}
+
+///
+/// Parse a comma-separated list of symbols, e.g. `AAPL,msft`.
+///
+fn parse_symbols(symbols: &str) -> Vec<String> {
+    symbols
+        .split(',')
+        .map(|s| s.trim().to_uppercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Longest symbol accepted, Yahoo's are much shorter
+const MAX_SYMBOL_LEN: usize = 16;
+
+///
+/// Normalize a single symbol, `None` unless it's a ticker of up to `MAX_SYMBOL_LEN` letters,
+/// digits and `.^=-`, e.g. `BRK-B`, `^GSPC` or `EURUSD=X`.
+///
+fn parse_symbol(symbol: &str) -> Option<String> {
+    let symbol = symbol.trim().to_uppercase();
+    let valid = |c: char| c.is_ascii_uppercase() || c.is_ascii_digit() || ".^=-".contains(c);
+    if symbol.is_empty() || symbol.len() > MAX_SYMBOL_LEN || !symbol.chars().all(valid) {
+        None
+    } else {
+        Some(symbol)
+    }
+}
+
+async fn tail(req: Request<AppState>) -> tide::Result {
+    let n = req
+        .param("n")?
+        .parse()
+        .ok()
+        .filter(|n| *n <= api::MAX_RECORDS)
+        .ok_or_else(|| api::bad_request(format!("n must be 0 to {}", api::MAX_RECORDS)))?;
+    let query: SymbolQuery = req.query()?;
+    let symbols = query
+        .symbol
+        .as_deref()
+        .map(parse_symbols)
+        .unwrap_or_default();
 
     let data: Vec<PerformanceIndicators> = {
-        let storage = req.state();
-        storage.call(BufferDataRequest(n)).await?
+        let storage = &req.state().buffer;
+        storage.call(BufferDataRequest { n, symbols }).await?
+    };
+    api::list(data)
+}
+
+///
+/// Server-sent events of new indicators, `?symbol=AAPL,MSFT` to only get some symbols. Clients
+/// that reconnect with a `Last-Event-ID` header get the buffered records they missed first.
+///
+async fn stream(req: Request<AppState>) -> tide::Result {
+    let query: SymbolQuery = req.query()?;
+    let symbols = query
+        .symbol
+        .as_deref()
+        .map(parse_symbols)
+        .unwrap_or_default();
+    let after = req
+        .header("Last-Event-ID")
+        .and_then(|id| id.last().as_str().parse().ok());
+    Ok(tide::sse::upgrade(req, move |req, sender| {
+        stream_events(req, sender, after, symbols.clone())
+    }))
+}
+
+async fn stream_events(
+    req: Request<AppState>,
+    sender: tide::sse::Sender,
+    after: Option<u64>,
+    symbols: Vec<String>,
+) -> tide::Result<()> {
+    let (tx, rx) = async_std::channel::bounded(STREAM_QUEUE);
+    let history = req
+        .state()
+        .buffer
+        .call(StreamRequest {
+            after,
+            symbols,
+            sender: tx,
+        })
+        .await?;
+    for (id, indicators) in history {
+        send_event(&sender, id, &indicators).await?;
+    }
+    while let Ok((id, indicators)) = rx.recv().await {
+        send_event(&sender, id, &indicators).await?;
+    }
+    Ok(())
+}
+
+async fn send_event(
+    sender: &tide::sse::Sender,
+    id: u64,
+    indicators: &PerformanceIndicators,
+) -> tide::Result<()> {
+    let data = serde_json::to_string(indicators)?;
+    sender
+        .send("indicators", data, Some(&id.to_string()))
+        .await?;
+    Ok(())
+}
+
+///
+/// A WebSocket client: its commands go to its own `ClientSession`, which sends back the updates it
+/// subscribed to. See `websocket::Command` for the protocol.
+///
+async fn websocket(
+    req: Request<AppState>,
+    mut connection: WebSocketConnection,
+) -> tide::Result<()> {
+    let (tx, rx) = async_std::channel::bounded(CLIENT_QUEUE);
+    let state = req.state();
+    // without authentication, everyone is an admin
+    let scope = req.ext::<Scope>().copied().unwrap_or(Scope::Admin);
+    let mut session = ClientSession::new(state.latest.clone(), state.scheduler.clone(), scope, tx)
+        .start()
+        .await?;
+    let writer = connection.clone();
+    let updates = async_std::task::spawn(async move {
+        while let Ok(update) = rx.recv().await {
+            if writer.send_json(&update).await.is_err() {
+                return;
+            }
+        }
+        // the session stopped
+        let _ = writer.send(Message::Close(None)).await;
+    });
+    while let Some(Ok(message)) = connection.next().await {
+        if let Message::Text(text) = message {
+            if session.send(Received(text)).is_err() {
+                break;
+            }
+        }
+    }
+    let _ = session.stop(None);
+    updates.await;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct SymbolSummary {
+    #[serde(flatten)]
+    watched: WatchedSymbol,
+    latest: Option<PerformanceIndicators>,
+}
+
+///
+/// The watchlist with the latest indicators of each symbol.
+///
+async fn list_symbols(req: Request<AppState>) -> tide::Result {
+    let mut summaries = vec![];
+    for watched in req.state().scheduler.call(Watchlist).await? {
+        let latest = req
+            .state()
+            .buffer
+            .call(LatestIndicatorsRequest(watched.symbol.clone()))
+            .await?;
+        summaries.push(SymbolSummary { watched, latest });
+    }
+    api::list(summaries)
+}
+
+async fn latest_indicators(req: Request<AppState>) -> tide::Result {
+    let symbol = req.param("symbol")?.to_uppercase();
+    match req
+        .state()
+        .buffer
+        .call(LatestIndicatorsRequest(symbol.clone()))
+        .await?
+    {
+        Some(indicators) => api::data(StatusCode::Ok, indicators),
+        None => api::error(
+            StatusCode::NotFound,
+            format!("no indicators of '{}'", symbol),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+///
+/// The buffered indicators of a symbol, newest first.
+///
+async fn history(req: Request<AppState>) -> tide::Result {
+    let HistoryQuery { from, to, limit } = req.query()?;
+    if limit.is_some_and(|limit| limit > api::MAX_RECORDS) {
+        return Err(api::bad_request(format!(
+            "limit must be at most {}",
+            api::MAX_RECORDS
+        )));
+    }
+    if from.zip(to).is_some_and(|(from, to)| from > to) {
+        return Err(api::bad_request("from must not be after to"));
+    }
+    let request = HistoryRequest {
+        symbol: req.param("symbol")?.to_uppercase(),
+        from,
+        to,
+        limit,
+    };
+    let data = req.state().buffer.call(request).await?;
+    api::list(data)
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ExportData {
+    #[default]
+    Indicators,
+    Quotes,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    symbol: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    data: ExportData,
+    /// Write numbers unformatted, whatever the `FileSink` does
+    #[serde(default)]
+    raw: bool,
+}
+
+///
+/// A CSV download of the buffered indicators of a symbol, or of the quotes last downloaded for
+/// it, oldest first. The columns are those of the `FileSink`. The body is streamed, one record at
+/// a time.
+///
+async fn export_csv(req: Request<AppState>) -> tide::Result {
+    let query: ExportQuery = req.query()?;
+    let symbol = query
+        .symbol
+        .as_deref()
+        .and_then(parse_symbol)
+        .ok_or_else(|| api::bad_request("a symbol is required"))?;
+    if query.from.zip(query.to).is_some_and(|(from, to)| from > to) {
+        return Err(api::bad_request("from must not be after to"));
+    }
+    let format = if query.raw {
+        CsvFormat {
+            precision: None,
+            pretty: false,
+            ..req.state().format
+        }
+    } else {
+        req.state().format
+    };
+
+    let records: Box<dyn Iterator<Item = Vec<String>> + Send + Sync> = match query.data {
+        ExportData::Indicators => {
+            let request = HistoryRequest {
+                symbol: symbol.clone(),
+                from: query.from,
+                to: query.to,
+                limit: None,
+            };
+            let records = req.state().buffer.call(request).await?;
+            let header = format.header(req.state().interval);
+            Box::new(
+                iter::once(header).chain(
+                    records
+                        .into_iter()
+                        .rev()
+                        .map(move |indicators| format.record(&indicators)),
+                ),
+            )
+        }
+        ExportData::Quotes => {
+            let quotes = req
+                .state()
+                .latest
+                .call(LatestQuotesRequest(symbol.clone()))
+                .await?;
+            let (from, to) = (query.from, query.to);
+            let symbol = symbol.clone();
+            Box::new(
+                iter::once(format.quote_header()).chain(
+                    quotes
+                        .into_iter()
+                        .filter(move |q| {
+                            let timestamp = q.timestamp as i64;
+                            from.is_none_or(|from| timestamp >= from.timestamp())
+                                && to.is_none_or(|to| timestamp <= to.timestamp())
+                        })
+                        .map(move |q| format.quote_record(&symbol, &q)),
+                ),
+            )
+        }
+    };
+
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_content_type("text/csv; charset=utf-8");
+    let data = match query.data {
+        ExportData::Indicators => "indicators",
+        ExportData::Quotes => "quotes",
+    };
+    response.insert_header(
+        "Content-Disposition",
+        format!("attachment; filename=\"{}-{}.csv\"", symbol, data),
+    );
+    let reader = BufReader::new(CsvReader::new(format, records));
+    response.set_body(Body::from_reader(reader, None));
+    Ok(response)
+}
+
+///
+/// The retention policy of the buffered records, how many there are and how many were evicted.
+///
+async fn buffer_stats(req: Request<AppState>) -> tide::Result {
+    let stats = req.state().buffer.call(RetentionRequest).await?;
+    api::data(StatusCode::Ok, stats)
+}
+
+async fn metrics(req: Request<AppState>) -> tide::Result {
+    let latest = req.state().latest.call(LatestRequest).await?;
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_content_type("text/plain; version=0.0.4");
+    response.set_body(metrics::prometheus(&latest));
+    Ok(response)
+}
+
+///
+/// Liveness: the process is up and serving requests.
+///
+async fn healthz(_req: Request<AppState>) -> tide::Result {
+    api::data(StatusCode::Ok, "ok")
+}
+
+///
+/// Readiness: the brokers pass messages on, the sinks are up and a download succeeded. Otherwise
+/// a 503 with the problems.
+///
+async fn readyz(req: Request<AppState>) -> tide::Result {
+    let report = req.state().health.call(StatusRequest).await?;
+    let mut problems = report.problems();
+    let brokers = async {
+        Broker::<QuoteRequest>::from_registry()
+            .await?
+            .call(Drain)
+            .await?;
+        Broker::<Quotes>::from_registry().await?.call(Drain).await?;
+        Broker::<PerformanceIndicators>::from_registry()
+            .await?
+            .call(Drain)
+            .await
+    };
+    match async_std::future::timeout(READY_TIMEOUT, brokers).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => problems.push(format!("broker failed: {}", e)),
+        Err(_) => problems.push("broker is stuck".to_string()),
+    }
+    if problems.is_empty() {
+        api::data(StatusCode::Ok, "ready")
+    } else {
+        api::error(
+            StatusCode::ServiceUnavailable,
+            format!("not ready: {}", problems.join(", ")),
+        )
+    }
+}
+
+///
+/// Uptime, downloads per symbol, sink health and actor restarts.
+///
+async fn status(req: Request<AppState>) -> tide::Result {
+    let report = req.state().health.call(StatusRequest).await?;
+    api::data(StatusCode::Ok, report)
+}
+
+///
+/// The health of the sinks, e.g. `degraded` while one waits for a restart.
+///
+async fn sink_health(req: Request<AppState>) -> tide::Result {
+    let sinks = req.state().health.call(HealthRequest).await?;
+    api::list(sinks)
+}
+
+#[derive(Debug, Deserialize)]
+struct SymbolQuery {
+    symbol: Option<String>,
+}
+
+///
+/// What a pause or resume applies to, all symbols if `symbol` is empty
+///
+#[derive(Debug, Serialize)]
+struct SchedulerChange {
+    symbol: Option<String>,
+    paused: bool,
+}
+
+async fn pause(req: Request<AppState>) -> tide::Result {
+    let query: SymbolQuery = req.query()?;
+    Broker::from_registry()
+        .await?
+        .publish(Pause(query.symbol.clone()))?;
+    let change = SchedulerChange {
+        symbol: query.symbol,
+        paused: true,
+    };
+    api::data(StatusCode::Accepted, change)
+}
+
+async fn resume(req: Request<AppState>) -> tide::Result {
+    let query: SymbolQuery = req.query()?;
+    Broker::from_registry()
+        .await?
+        .publish(Resume(query.symbol.clone()))?;
+    let change = SchedulerChange {
+        symbol: query.symbol,
+        paused: false,
     };
+    api::data(StatusCode::Accepted, change)
+}
+
+#[derive(Debug, Deserialize)]
+struct NewSymbol {
+    symbol: String,
+}
+
+///
+/// A symbol that was added to or removed from the watchlist
+///
+#[derive(Debug, Serialize)]
+struct WatchlistChange {
+    symbol: String,
+    watched: bool,
+}
+
+///
+/// Add a symbol to the watchlist: 201 if it's new, 200 if it was already watched.
+///
+async fn add_symbol(mut req: Request<AppState>) -> tide::Result {
+    let NewSymbol { symbol } = req.body_json().await?;
+    let Some(symbol) = parse_symbol(&symbol) else {
+        return Err(api::bad_request(format!("invalid symbol '{}'", symbol)));
+    };
+    let added = req
+        .state()
+        .scheduler
+        .call(AddSymbol(symbol.clone()))
+        .await?;
+    let status = if added {
+        StatusCode::Created
+    } else {
+        StatusCode::Ok
+    };
+    let change = WatchlistChange {
+        symbol,
+        watched: true,
+    };
+    api::data(status, change)
+}
+
+async fn remove_symbol(req: Request<AppState>) -> tide::Result {
+    let symbol = req.param("symbol")?.to_uppercase();
+    let removed = req
+        .state()
+        .scheduler
+        .call(RemoveSymbol(symbol.clone()))
+        .await?;
+    if removed {
+        let change = WatchlistChange {
+            symbol,
+            watched: false,
+        };
+        api::data(StatusCode::Ok, change)
+    } else {
+        api::error(
+            StatusCode::NotFound,
+            format!("'{}' isn't on the watchlist", symbol),
+        )
+    }
+}
+
+///
+/// The dashboard, a single page following the watchlist over the API
+///
+const DASHBOARD: &str = include_str!("../dashboard.html");
+
+async fn dashboard(_req: Request<AppState>) -> tide::Result {
     let mut response = Response::new(StatusCode::Ok);
-    response.set_body(Body::from_json(&data)?);
+    response.set_content_type(tide::http::mime::HTML);
+    response.set_body(DASHBOARD);
     Ok(response)
 }
 
+async fn openapi(_req: Request<AppState>) -> tide::Result {
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_content_type(tide::http::mime::JSON);
+    response.set_body(api::OPENAPI);
+    Ok(response)
+}
+
+///
+/// The HTTP API, see `openapi.json`. With tokens, requests need a bearer token.
+///
+fn server(state: AppState, tokens: Option<Tokens>) -> Server<AppState> {
+    let mut app = tide::with_state(state);
+    app.with(tide::utils::After(api::envelope_errors));
+    if let Some(tokens) = tokens {
+        app.with(TokenAuth { tokens });
+    }
+    app.at("/").get(dashboard);
+    app.at("openapi.json").get(openapi);
+    app.at("tail/:n").get(tail);
+    app.at("stream").get(stream);
+    app.at("ws").get(WebSocket::new(websocket));
+    app.at("symbols").get(list_symbols);
+    app.at("indicators/:symbol").get(history);
+    app.at("indicators/:symbol/latest").get(latest_indicators);
+    app.at("export.csv").get(export_csv);
+    app.at("buffer").get(buffer_stats);
+    app.at("metrics").get(metrics);
+    app.at("sinks").get(sink_health);
+    app.at("healthz").get(healthz);
+    app.at("readyz").get(readyz);
+    app.at("status").get(status);
+    app.at("scheduler/pause").post(pause);
+    app.at("scheduler/resume").post(resume);
+    app.at("symbols").post(add_symbol);
+    app.at("symbols/:symbol").delete(remove_symbol);
+    app
+}
+
+///
+/// Bind the HTTP API to `--listen` and `--port`, over TLS with a certificate. This fails right
+/// away if the address can't be used or the certificate can't be loaded.
+///
+async fn bind(app: Server<AppState>, opts: &Opts) -> io::Result<Box<dyn Listener<AppState>>> {
+    let address = (opts.listen.as_str(), opts.port);
+    let mut listener: Box<dyn Listener<AppState>> = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => {
+            let addrs: Vec<_> = address.to_socket_addrs()?.collect();
+            Box::new(
+                TlsListener::build()
+                    .addrs(&addrs[..])
+                    .cert(cert)
+                    .key(key)
+                    .finish()?,
+            )
+        }
+        _ => Box::new(address.to_listener()?),
+    };
+    listener.bind(app).await?;
+    Ok(listener)
+}
+
+///
+/// Stop scheduling and drain the pipeline stage by stage, brokers included, so every quote that
+/// was requested ends up in the files.
+///
+async fn drain(
+    scheduler: &Addr<Scheduler>,
+    downloader: &Addr<StockDataDownloader>,
+    processor: &Addr<StockDataProcessor>,
+    sinks: &[Caller<Drain>],
+) -> Result<()> {
+    scheduler.call(Pause(None)).await?;
+    Broker::<QuoteRequest>::from_registry()
+        .await?
+        .call(Drain)
+        .await?;
+    downloader.call(Drain).await?;
+    Broker::<Quotes>::from_registry().await?.call(Drain).await?;
+    processor.call(Drain).await?;
+    Broker::<PerformanceIndicators>::from_registry()
+        .await?
+        .call(Drain)
+        .await?;
+    for sink in sinks {
+        sink.call(Drain).await?;
+    }
+    Ok(())
+}
+
+///
+/// Download the quotes of all symbols once and write them and their indicators as Parquet files.
+/// The symbols that downloaded are written even if others failed, which makes it an error.
+///
+async fn export(
+    symbols: &[String],
+    from: DateTime<Utc>,
+    interval: Interval,
+    processor: &StockDataProcessor,
+    dir: &Path,
+) -> Result<()> {
+    let mut writer = ParquetWriter::new(dir, usize::MAX);
+    let to = Utc::now();
+    let mut failed = vec![];
+    for symbol in symbols {
+        let request = QuoteRequest {
+            symbol: symbol.clone(),
+            from,
+            to,
+            interval,
+        };
+        let (mut quotes, error) = fetch_quotes(&request).await;
+        if error.is_some() {
+            failed.push(symbol.as_str());
+            continue;
+        }
+        writer.add_quotes(&quotes)?;
+        if let Some(indicators) = processor.indicators(&mut quotes).await {
+            writer.add_indicators(&indicators)?;
+        }
+    }
+    writer.flush()?;
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "Couldn't download {} of {} symbols: {}",
+            failed.len(),
+            symbols.len(),
+            failed.join(", ")
+        )))
+    }
+}
+
 ///
 /// Main!
 ///
 #[xactor::main]
 async fn main() -> Result<()> {
+    let started = Utc::now();
     let opts: Opts = Opts::parse();
+    opts.adjust.check(opts.price_field).map_err(Error::msg)?;
     let from: DateTime<Utc> = opts.from.parse().expect("Couldn't parse 'from' date");
-    let symbols: Vec<String> = opts.symbols.split(',').map(|s| s.to_owned()).collect();
+    let symbols: Vec<String> = opts
+        .symbols
+        .split(',')
+        .map(|s| {
+            parse_symbol(s)
+                .ok_or_else(|| Error::msg(format!("'{}' isn't a valid symbol", s.trim())))
+        })
+        .collect::<Result<_>>()?;
+    let calendar = opts
+        .calendar
+        .as_ref()
+        .map(Calendar::from_file)
+        .transpose()?;
+    let tokens = opts
+        .tokens
+        .as_ref()
+        .map(Tokens::from_file)
+        .transpose()
+        .map_err(|e| Error::msg(format!("Couldn't read the token file: {}", e)))?;
+    // rather than falling back to `--symbols` and overwriting the file with them
+    if let Some(path) = &opts.watchlist {
+        match read_watchlist(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::msg(format!(
+                    "Couldn't read the watchlist '{}': {}",
+                    path.display(),
+                    e
+                )));
+            }
+            _ => {}
+        }
+    }
+    let format = CsvFormat {
+        delimiter: opts.delimiter,
+        precision: opts.precision,
+        pretty: opts.pretty,
+    };
+    let output = opts.format;
+    let settings = StockDataProcessor {
+        price_field: opts.price_field,
+        adjustment: opts.adjust,
+        output,
+        format,
+    };
+    if let Some(dir) = &opts.export {
+        return export(&symbols, from, opts.interval, &settings, dir).await;
+    }
+    let shutdown = shutdown::signals()?;
 
     // Start actors. Supervisors also keep those actors alive
-    let _downloader = Supervisor::start(|| StockDataDownloader).await;
-    let _processor = Supervisor::start(|| StockDataProcessor).await;
-    let _sink = Supervisor::start(|| FileSink {
-        filename: format!("{}.csv", Utc::now().timestamp()), // create a unique file name every time
-        writer: None,
-    })
-    .await;
+    let downloader = supervise("StockDataDownloader", || StockDataDownloader).await?;
+    let processor = supervise("StockDataProcessor", move || settings.clone()).await?;
+    // before the sinks, which report their health when they start
+    let health = supervise("HealthMonitor", move || HealthMonitor::new(started)).await?;
+    // by default, create a unique file name every time
+    let template = opts.output.clone().unwrap_or_else(|| {
+        format!(
+            "{{timestamp}}.{}",
+            output.to_possible_value().unwrap().get_name()
+        )
+    });
+    let output_options = OutputOptions {
+        append: opts.append,
+        rotation: opts.rotate,
+        gzip: opts.gzip,
+        flush: opts.flush,
+        fsync: opts.fsync,
+        ..OutputOptions::new(&template)
+    };
+    let backoff = Backoff {
+        max_restarts: opts.sink_restarts,
+        ..Default::default()
+    };
+    let (file_sink, json_lines_sink) = match output {
+        OutputFormat::Csv => {
+            let sink = supervise("FileSink", move || FileSink {
+                files: RotatingFiles::new(output_options.clone()),
+                interval: opts.interval,
+                format,
+                health: Health::new("FileSink", backoff),
+            })
+            .await?;
+            (Some(sink), None)
+        }
+        OutputFormat::Jsonl => {
+            let sink = supervise("JsonLinesSink", move || JsonLinesSink {
+                files: RotatingFiles::new(output_options.clone()),
+                health: Health::new("JsonLinesSink", backoff),
+            })
+            .await?;
+            (None, Some(sink))
+        }
+    };
+    let sqlite_sink = match opts.sqlite.clone() {
+        Some(path) => {
+            let quotes = opts.sqlite_quotes;
+            Some(
+                supervise("SqliteSink", move || SqliteSink {
+                    path: path.clone(),
+                    quotes,
+                    connection: None,
+                    health: Health::new("SqliteSink", backoff),
+                })
+                .await?,
+            )
+        }
+        None => None,
+    };
+    let parquet_flush = Duration::from_secs(opts.parquet_flush);
+    let parquet_sink = match opts.parquet.clone() {
+        Some(dir) => Some(
+            supervise("ParquetSink", move || ParquetSink {
+                writer: ParquetWriter::new(&dir, PARQUET_ROWS_PER_FILE),
+                flush_interval: parquet_flush,
+                health: Health::new("ParquetSink", backoff),
+            })
+            .await?,
+        ),
+        None => None,
+    };
+    let influx_sink = match opts.influx.clone() {
+        Some(target) => {
+            let sink = supervise("InfluxSink", move || {
+                InfluxSink::new(target.clone(), Health::new("InfluxSink", backoff))
+            })
+            .await?;
+            Some(sink)
+        }
+        None => None,
+    };
+    let sinks: Vec<Caller<Drain>> = file_sink
+        .iter()
+        .map(|sink| sink.caller())
+        .chain(json_lines_sink.iter().map(|sink| sink.caller()))
+        .chain(sqlite_sink.iter().map(|sink| sink.caller()))
+        .chain(parquet_sink.iter().map(|sink| sink.caller()))
+        .chain(influx_sink.iter().map(|sink| sink.caller()))
+        .collect();
+
+    let retention = Retention {
+        per_symbol: opts.buffer_per_symbol,
+        max_age: opts.buffer_max_age,
+        max_bytes: opts.buffer_memory,
+    };
+    let data_actor = supervise("BufferSink", move || BufferSink::new(retention)).await?;
+    let latest = supervise("LatestSink", LatestSink::default).await?;
 
-    let data_actor = Supervisor::start(move || BufferSink {
-        data_sink: VecDeque::with_capacity(BUFFER_SIZE),
+    // CSV header
+    if output == OutputFormat::Csv {
+        println!("{}", format.line(&format.header(opts.interval)));
+    }
+    let (interval, post_close_refresh) = (opts.interval, opts.post_close_refresh);
+    let watchlist = opts.watchlist.clone();
+    // a restarted scheduler continues with the symbols added or removed at runtime
+    let symbols = Arc::new(Mutex::new(symbols));
+    let scheduler = supervise("Scheduler", move || {
+        let current = symbols.lock().unwrap().clone();
+        Scheduler::new(
+            &current,
+            from,
+            interval,
+            Duration::from_secs(30),
+            calendar.clone(),
+            post_close_refresh,
+            watchlist.clone(),
+        )
+        .with_shared_symbols(symbols.clone())
     })
     .await?;
 
-    let mut app = tide::with_state(data_actor.clone());
-    let _http_endpoint = async_std::task::spawn(async {
-        app.at("tail/:n").get(tail);
-        app.listen("localhost:4321").await
+    let app = server(
+        AppState {
+            buffer: data_actor.clone(),
+            latest: latest.clone(),
+            health: health.clone(),
+            scheduler: scheduler.clone(),
+            format,
+            interval: opts.interval,
+        },
+        tokens,
+    );
+    let mut listener = bind(app, &opts).await.map_err(|e| {
+        Error::msg(format!(
+            "Couldn't serve the API on {}:{}: {}",
+            opts.listen, opts.port, e
+        ))
+    })?;
+    for info in listener.info() {
+        eprintln!("API listening on {}", info);
+    }
+    let (api_failure, api_failed) = async_std::channel::bounded(1);
+    let http_endpoint = async_std::task::spawn(async move {
+        if let Err(e) = listener.accept().await {
+            let _ = api_failure.send(e).await;
+        }
     });
 
-    // CSV header
-    println!("period start,symbol,price,change %,min,max,30d avg");
-    let mut interval = stream::interval(Duration::from_secs(30));
-    'outer: while interval.next().await.is_some() {
-        let now = Utc::now(); // Period end for this fetch
-        for symbol in &symbols {
-            if let Err(e) = Broker::from_registry().await?.publish(QuoteRequest {
-                symbol: symbol.clone(),
-                from,
-                to: now,
-            }) {
-                eprint!("{}", e);
-                break 'outer;
+    // from here on, the actors do the work, until a signal or the API fails
+    let signal = async {
+        let _ = shutdown.recv().await;
+        None
+    };
+    let failure = async { api_failed.recv().await.ok() };
+    let api_error = signal.race(failure).await;
+    match &api_error {
+        Some(e) => eprintln!("The API failed, shutting down: {}", e),
+        None => eprintln!("Shutting down, press Ctrl-C again to exit right away"),
+    }
+    let deadline = Duration::from_secs(opts.shutdown_timeout);
+    match async_std::future::timeout(deadline, drain(&scheduler, &downloader, &processor, &sinks))
+        .await
+    {
+        Ok(result) => result?,
+        Err(_) => eprintln!(
+            "Couldn't drain within {}s, unwritten data is lost",
+            deadline.as_secs()
+        ),
+    }
+    http_endpoint.cancel().await;
+    match api_error {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use health::{SinkHealth, Status};
+
+    #[async_std::test]
+    async fn test_JsonLinesSink() {
+        let filename = std::env::temp_dir()
+            .join(format!("sink-{}.jsonl", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let sink = JsonLinesSink {
+            files: RotatingFiles::new(OutputOptions::new(&filename)),
+            ..Default::default()
+        }
+        .start()
+        .await
+        .unwrap();
+        let mut broker = Broker::from_registry().await.unwrap();
+        for symbol in ["AAPL", "MSFT"] {
+            broker
+                .publish(PerformanceIndicators {
+                    symbol: symbol.to_string(),
+                    timestamp: Utc.timestamp_opt(0, 0).unwrap(),
+                    price: Price::one(),
+                    pct_change: Price::zero(),
+                    period_min: Price::one(),
+                    period_max: Price::from_usize(2),
+                    last_sma: Price::one(),
+                })
+                .unwrap();
+        }
+
+        broker.call(Drain).await.unwrap();
+        sink.call(Drain).await.unwrap();
+        let content = std::fs::read_to_string(&filename).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["symbol"], "MSFT");
+        assert_eq!(records[1]["timestamp"], "1970-01-01T00:00:00Z");
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_FileSink_health() {
+        let monitor = HealthMonitor::new(Utc::now()).start().await.unwrap();
+        // a directory can't be created below a file
+        let file = std::env::temp_dir().join(format!("health-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        let _sink = FileSink {
+            files: RotatingFiles::new(OutputOptions::new(&file.join("out.csv").to_string_lossy())),
+            health: Health::new(
+                "FileSink",
+                Backoff {
+                    max_restarts: 1,
+                    initial: Duration::from_millis(10),
+                    ..Default::default()
+                },
+            ),
+            ..Default::default()
+        }
+        .start()
+        .await
+        .unwrap();
+
+        Broker::<SinkHealth>::from_registry()
+            .await
+            .unwrap()
+            .call(Drain)
+            .await
+            .unwrap();
+        let file_sink =
+            |health: Vec<SinkHealth>| health.into_iter().find(|h| h.sink == "FileSink").unwrap();
+        let health = file_sink(monitor.call(HealthRequest).await.unwrap());
+        assert_eq!(health.status, Status::Degraded);
+        assert_eq!(health.restarts, 1);
+        assert!(health.error.as_ref().unwrap().starts_with("couldn't open"));
+
+        // the restart fails too, which was the last one
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        Broker::<SinkHealth>::from_registry()
+            .await
+            .unwrap()
+            .call(Drain)
+            .await
+            .unwrap();
+        let health = file_sink(monitor.call(HealthRequest).await.unwrap());
+        assert_eq!(health.status, Status::Failed);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    async fn state() -> AppState {
+        AppState {
+            buffer: BufferSink::new(Retention {
+                per_symbol: 1,
+                ..Default::default()
+            })
+            .start()
+            .await
+            .unwrap(),
+            latest: LatestSink::default().start().await.unwrap(),
+            health: HealthMonitor::new(Utc::now()).start().await.unwrap(),
+            scheduler: Scheduler::new(
+                &[],
+                Utc::now(),
+                Interval::default(),
+                Duration::from_secs(30),
+                None,
+                false,
+                None,
+            )
+            .start()
+            .await
+            .unwrap(),
+            format: CsvFormat {
+                pretty: true,
+                ..Default::default()
+            },
+            interval: Interval::default(),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_StockDataProcessor_indicators() {
+        let processor = StockDataProcessor {
+            price_field: PriceField::Close,
+            adjustment: Adjustment::None,
+            output: OutputFormat::Csv,
+            format: CsvFormat::default(),
+        };
+        let quote = |timestamp: u64, close: f64| yahoo::Quote {
+            timestamp,
+            open: close,
+            high: close,
+            low: close,
+            volume: 100,
+            close,
+            adjclose: close,
+        };
+        let mut quotes = Quotes {
+            symbol: "AAPL".to_string(),
+            quotes: vec![quote(2, 2.0), quote(1, 1.0)],
+            dividends: vec![],
+        };
+        let indicators = processor.indicators(&mut quotes).await.unwrap();
+        assert_eq!(indicators.timestamp.timestamp(), 2);
+        assert_eq!(indicators.price, Price::from_usize(2));
+        assert_eq!(indicators.period_min, Price::one());
+
+        quotes.quotes.clear();
+        assert!(processor.indicators(&mut quotes).await.is_none());
+    }
+
+    ///
+    /// `Decimal` can't represent `NaN`, so the quotes are rejected rather than calculated on a
+    /// shorter window.
+    ///
+    #[cfg(feature = "decimal")]
+    #[async_std::test]
+    async fn test_StockDataProcessor_indicators_unrepresentable() {
+        let processor = StockDataProcessor {
+            price_field: PriceField::Close,
+            adjustment: Adjustment::None,
+            output: OutputFormat::Csv,
+            format: CsvFormat::default(),
+        };
+        let mut quotes = Quotes {
+            symbol: "AAPL".to_string(),
+            quotes: vec![
+                yahoo::Quote {
+                    timestamp: 1,
+                    open: 1.0,
+                    high: 1.0,
+                    low: 1.0,
+                    volume: 100,
+                    close: 1.0,
+                    adjclose: 1.0,
+                },
+                yahoo::Quote {
+                    timestamp: 2,
+                    open: 1.0,
+                    high: 1.0,
+                    low: 1.0,
+                    volume: 100,
+                    close: f64::NAN,
+                    adjclose: 1.0,
+                },
+            ],
+            dividends: vec![],
+        };
+        assert!(processor.indicators(&mut quotes).await.is_none());
+    }
+
+    #[test]
+    fn test_parse_symbol() {
+        for (symbol, parsed) in [
+            (" brk-b ", "BRK-B"),
+            ("^gspc", "^GSPC"),
+            ("EURUSD=X", "EURUSD=X"),
+        ] {
+            assert_eq!(parse_symbol(symbol).as_deref(), Some(parsed));
+        }
+        for symbol in [
+            "",
+            "A,B",
+            "A B",
+            "<SVG/ONLOAD=1>",
+            "A&B",
+            "A\u{7}",
+            "TOOLONGTOBEATICKER",
+        ] {
+            assert!(parse_symbol(symbol).is_none(), "{:?}", symbol);
+        }
+    }
+
+    #[test]
+    fn test_Opts_price_field() {
+        let parse = |args: &[&str]| {
+            let opts =
+                Opts::parse_from(["app", "--from", "2020-01-01T00:00:00Z"].iter().chain(args));
+            (
+                opts.price_field,
+                opts.adjust.check(opts.price_field).is_ok(),
+            )
+        };
+        assert_eq!(parse(&[]), (PriceField::Adjclose, true));
+        assert_eq!(
+            parse(&["--adjust", "total-return"]),
+            (PriceField::Close, true)
+        );
+        let adjclose = ["--adjust", "total-return", "--price-field", "adjclose"];
+        assert_eq!(parse(&adjclose), (PriceField::Adjclose, false));
+    }
+
+    #[async_std::test]
+    async fn test_bind() {
+        let app = || async { tide::with_state(state().await) };
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port().to_string();
+        let args = [
+            "app",
+            "--from",
+            "2020-01-01T00:00:00Z",
+            "--listen",
+            "127.0.0.1",
+        ];
+
+        let opts = Opts::parse_from(args.iter().chain(&["--port", "0"]));
+        let listener = bind(app().await, &opts).await.unwrap();
+        assert!(listener.info()[0]
+            .to_string()
+            .starts_with("http://127.0.0.1:"));
+        let opts = Opts::parse_from(args.iter().chain(&["--port", &port]));
+        let error = bind(app().await, &opts).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        let tls = ["--tls-cert", "missing.pem", "--tls-key", "missing.key"];
+        let opts = Opts::parse_from(args.iter().chain(&tls));
+        assert!(bind(app().await, &opts).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_server() {
+        let app = server(state().await, None);
+        let get = |path: String| {
+            let req = tide::http::Request::new(
+                tide::http::Method::Get,
+                tide::http::Url::parse("http://localhost")
+                    .unwrap()
+                    .join(&path)
+                    .unwrap(),
+            );
+            let app = app.clone();
+            async move {
+                let mut res: tide::http::Response = app.respond(req).await.unwrap();
+                (res.status(), res.body_string().await.unwrap())
+            }
+        };
+
+        let (status, body) = get("/tail/3".to_string()).await;
+        assert_eq!(status, StatusCode::Ok);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["data"], serde_json::json!([]));
+        assert_eq!(body["meta"]["count"], 0);
+        for path in ["/tail/three", "/tail/1001", "/indicators/AAPL?limit=5000"] {
+            let (status, body) = get(path.to_string()).await;
+            assert_eq!(status, StatusCode::BadRequest, "{}", path);
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["error"]["status"], 400);
+        }
+
+        let (status, body) = get("/".to_string()).await;
+        assert_eq!(status, StatusCode::Ok);
+        assert!(body.contains("/stream") && body.contains("/symbols"));
+
+        // every documented endpoint is routed, the streams aside
+        let spec: serde_json::Value = serde_json::from_str(api::OPENAPI).unwrap();
+        for (path, operations) in spec["paths"].as_object().unwrap() {
+            if operations.get("get").is_none() || path == "/stream" || path == "/ws" {
+                continue;
             }
+            let path = path.replace("{n}", "3").replace("{symbol}", "AAPL");
+            let (_, body) = get(path.clone()).await;
+            assert!(!body.is_empty(), "{} isn't routed", path);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_export_csv() {
+        let mut state = state().await;
+        state.buffer = BufferSink::new(Retention::default()).start().await.unwrap();
+        for (day, price) in [(1, 1.5), (2, 2.25)] {
+            let indicators = PerformanceIndicators {
+                symbol: "EXPT".to_string(),
+                timestamp: Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap(),
+                price: Price::from_f64(price).unwrap(),
+                pct_change: Price::zero(),
+                period_min: Price::one(),
+                period_max: Price::from_f64(price).unwrap(),
+                last_sma: Price::one(),
+            };
+            state.buffer.send(indicators).unwrap();
+        }
+        state.latest = LatestSink::default().start().await.unwrap();
+        let quote = |timestamp: u64, close: f64| yahoo::Quote {
+            timestamp,
+            open: close,
+            high: close,
+            low: close,
+            volume: 100,
+            close,
+            adjclose: close,
+        };
+        let quotes = Quotes {
+            symbol: "EXPT".to_string(),
+            quotes: vec![quote(1_704_067_200, 1.5), quote(1_704_153_600, 2.25)],
+            dividends: vec![],
+        };
+        state.latest.send(quotes).unwrap();
+        // a failed download doesn't replace the quotes
+        state
+            .latest
+            .send(Quotes {
+                symbol: "EXPT".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        let app = server(state, None);
+        let get = |path: &str| {
+            let req = tide::http::Request::new(
+                tide::http::Method::Get,
+                tide::http::Url::parse("http://localhost")
+                    .unwrap()
+                    .join(path)
+                    .unwrap(),
+            );
+            let app = app.clone();
+            async move {
+                let mut res: tide::http::Response = app.respond(req).await.unwrap();
+                let disposition = res
+                    .header("Content-Disposition")
+                    .map(|h| h.last().to_string());
+                (res.status(), disposition, res.body_string().await.unwrap())
+            }
+        };
+
+        let (status, disposition, body) = get("/export.csv?symbol=expt").await;
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(
+            disposition.as_deref(),
+            Some("attachment; filename=\"EXPT-indicators.csv\"")
+        );
+        assert_eq!(
+            body.lines().collect::<Vec<_>>(),
+            [
+                "period start,symbol,price,change %,min,max,30d avg",
+                "2024-01-01T00:00:00+00:00,EXPT,$1.50,0.00%,$1.00,$1.50,$1.00",
+                "2024-01-02T00:00:00+00:00,EXPT,$2.25,0.00%,$1.00,$2.25,$1.00",
+            ]
+        );
+        let (_, _, body) = get("/export.csv?symbol=EXPT&raw=true&from=2024-01-02T00:00:00Z").await;
+        assert_eq!(
+            body.lines().collect::<Vec<_>>(),
+            [
+                "timestamp,symbol,price,pct_change,period_min,period_max,last_sma",
+                "2024-01-02T00:00:00+00:00,EXPT,2.25,0,1,2.25,1",
+            ]
+        );
+        let (status, disposition, body) =
+            get("/export.csv?symbol=EXPT&data=quotes&raw=true&to=2024-01-01T12:00:00Z").await;
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(
+            disposition.as_deref(),
+            Some("attachment; filename=\"EXPT-quotes.csv\"")
+        );
+        assert_eq!(
+            body.lines().collect::<Vec<_>>(),
+            [
+                "timestamp,symbol,open,high,low,close,adjclose,volume",
+                "2024-01-01T00:00:00+00:00,EXPT,1.5,1.5,1.5,1.5,1.5,100",
+            ]
+        );
+        let (_, _, body) = get("/export.csv?symbol=NONE&data=quotes&raw=true").await;
+        assert_eq!(
+            body,
+            "timestamp,symbol,open,high,low,close,adjclose,volume\n"
+        );
+        for path in [
+            "/export.csv",
+            "/export.csv?symbol=EXPT&from=2024-01-02T00:00:00Z&to=2024-01-01T00:00:00Z",
+            "/export.csv?symbol=EXPT&data=trades",
+        ] {
+            let (status, _, _) = get(path).await;
+            assert_eq!(status, StatusCode::BadRequest, "{}", path);
         }
     }
-    Ok(())
 }
@@ -0,0 +1,461 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{TcpStream, UdpSocket},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use xactor::*;
+use yahoo_finance_api as yahoo;
+
+use crate::{
+    health::{Health, Restart},
+    shutdown::Drain,
+    signal::Numeric,
+    PerformanceIndicators, Quotes,
+};
+
+/// A Prometheus gauge: name, help and value, if it converts to a float
+type Gauge = (
+    &'static str,
+    &'static str,
+    fn(&PerformanceIndicators) -> Option<f64>,
+);
+
+///
+/// The gauges of each symbol's indicators
+///
+const GAUGES: [Gauge; 6] = [
+    ("stock_price", "Last price of the period", |i| {
+        i.price.to_f64()
+    }),
+    (
+        "stock_pct_change",
+        "Change over the period, as a fraction",
+        |i| i.pct_change.to_f64(),
+    ),
+    ("stock_period_min", "Minimum price of the period", |i| {
+        i.period_min.to_f64()
+    }),
+    ("stock_period_max", "Maximum price of the period", |i| {
+        i.period_max.to_f64()
+    }),
+    (
+        "stock_last_sma",
+        "Last simple moving average (30 bars)",
+        |i| i.last_sma.to_f64(),
+    ),
+    (
+        "stock_timestamp_seconds",
+        "Time of the last quote, in seconds since the epoch",
+        |i| Some(i.timestamp.timestamp() as f64),
+    ),
+];
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+///
+/// Render indicators in the Prometheus text exposition format.
+///
+pub fn prometheus(indicators: &[PerformanceIndicators]) -> String {
+    let mut out = String::new();
+    for (name, help, value) in GAUGES {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        // a missing sample is better than a wrong one
+        for (i, value) in indicators.iter().filter_map(|i| Some((i, value(i)?))) {
+            let _ = writeln!(
+                out,
+                "{}{{symbol=\"{}\"}} {}",
+                name,
+                escape_label(&i.symbol),
+                value
+            );
+        }
+    }
+    out
+}
+
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+///
+/// Render indicators as a line of the InfluxDB line protocol, with a nanosecond timestamp. `None`
+/// if a value doesn't convert to a float.
+///
+pub fn line_protocol(i: &PerformanceIndicators) -> Option<String> {
+    Some(format!(
+        "indicators,symbol={} price={},pct_change={},period_min={},period_max={},last_sma={} {}",
+        escape_tag(&i.symbol),
+        i.price.to_f64()?,
+        i.pct_change.to_f64()?,
+        i.period_min.to_f64()?,
+        i.period_max.to_f64()?,
+        i.last_sma.to_f64()?,
+        i.timestamp.timestamp_nanos_opt().unwrap_or_default()
+    ))
+}
+
+///
+/// Actor that keeps the latest indicators of each symbol, and the quotes they were calculated
+/// from
+///
+#[derive(Default, Debug)]
+pub struct LatestSink {
+    latest: BTreeMap<String, PerformanceIndicators>,
+    quotes: BTreeMap<String, Vec<yahoo::Quote>>,
+}
+
+#[async_trait]
+impl Actor for LatestSink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<PerformanceIndicators>().await?;
+        ctx.subscribe::<Quotes>().await
+    }
+}
+
+#[async_trait]
+impl Handler<Quotes> for LatestSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Quotes) {
+        // a failed download has no quotes, keep the previous ones
+        if !msg.quotes.is_empty() {
+            self.quotes.insert(msg.symbol, msg.quotes);
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<PerformanceIndicators> for LatestSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        self.latest.insert(msg.symbol.clone(), msg);
+    }
+}
+
+///
+/// Get the latest indicators of all symbols, sorted by symbol
+///
+#[derive(Default, Debug)]
+#[message(result = "Vec<PerformanceIndicators>")]
+pub struct LatestRequest;
+
+#[async_trait]
+impl Handler<LatestRequest> for LatestSink {
+    async fn handle(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        _msg: LatestRequest,
+    ) -> Vec<PerformanceIndicators> {
+        self.latest.values().cloned().collect()
+    }
+}
+
+///
+/// Get the latest quotes of a symbol, sorted by time, empty if none were downloaded yet
+///
+#[derive(Debug)]
+#[message(result = "Vec<yahoo::Quote>")]
+pub struct LatestQuotesRequest(pub String);
+
+#[async_trait]
+impl Handler<LatestQuotesRequest> for LatestSink {
+    async fn handle(
+        &mut self,
+        _ctx: &mut Context<Self>,
+        msg: LatestQuotesRequest,
+    ) -> Vec<yahoo::Quote> {
+        self.quotes.get(&msg.0).cloned().unwrap_or_default()
+    }
+}
+
+///
+/// Where to send line protocol: `udp://host:port`, `tcp://host:port` or a file to append to.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum InfluxTarget {
+    File(PathBuf),
+    Udp(String),
+    Tcp(String),
+}
+
+impl FromStr for InfluxTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("udp://") {
+            Ok(InfluxTarget::Udp(address.to_string()))
+        } else if let Some(address) = s.strip_prefix("tcp://") {
+            Ok(InfluxTarget::Tcp(address.to_string()))
+        } else if s.is_empty() {
+            Err("the target is empty".to_string())
+        } else {
+            Ok(InfluxTarget::File(PathBuf::from(
+                s.strip_prefix("file://").unwrap_or(s),
+            )))
+        }
+    }
+}
+
+/// How long connecting, and writing to a TCP target, may take
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum Connection {
+    File(BufWriter<File>),
+    Udp(UdpSocket),
+    Tcp(BufWriter<TcpStream>),
+}
+
+impl InfluxTarget {
+    ///
+    /// Open the file or connect to the address. Host names are resolved and TCP connections made
+    /// asynchronously, so an unreachable host doesn't block the executor.
+    ///
+    async fn connect(&self) -> io::Result<Connection> {
+        Ok(match self {
+            InfluxTarget::File(path) => Connection::File(BufWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            InfluxTarget::Udp(address) => {
+                let addresses: Vec<_> = async_std::net::ToSocketAddrs::to_socket_addrs(address)
+                    .await?
+                    .collect();
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(&addresses[..])?;
+                Connection::Udp(socket)
+            }
+            InfluxTarget::Tcp(address) => {
+                let stream =
+                    async_std::io::timeout(TIMEOUT, async_std::net::TcpStream::connect(address))
+                        .await?;
+                let stream = TcpStream::try_from(stream)?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                Connection::Tcp(BufWriter::new(stream))
+            }
+        })
+    }
+}
+
+impl Connection {
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            // flushed right away like TCP, so a crash doesn't lose the points written since startup
+            Connection::File(writer) => {
+                writeln!(writer, "{}", line)?;
+                writer.flush()
+            }
+            // one datagram per line, so a lost packet loses only one point
+            Connection::Udp(socket) => socket.send(format!("{}\n", line).as_bytes()).map(|_| ()),
+            Connection::Tcp(writer) => {
+                writeln!(writer, "{}", line)?;
+                writer.flush()
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::File(writer) => writer.flush(),
+            Connection::Udp(_) => Ok(()),
+            Connection::Tcp(writer) => writer.flush(),
+        }
+    }
+}
+
+///
+/// Actor for sending incoming messages as InfluxDB line protocol. If the target can't be reached
+/// or a write fails, it's reconnected with a backoff and messages are dropped until then, like
+/// with `FileSink`.
+///
+#[derive(Debug)]
+pub struct InfluxSink {
+    pub target: InfluxTarget,
+    pub health: Health,
+    connection: Option<Connection>,
+}
+
+impl InfluxSink {
+    pub fn new(target: InfluxTarget, health: Health) -> Self {
+        InfluxSink {
+            target,
+            health,
+            connection: None,
+        }
+    }
+
+    ///
+    /// Connect to the target, or restart later if that fails.
+    ///
+    async fn start(&mut self, ctx: &mut Context<Self>) {
+        match self.target.connect().await {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                self.health.up().await;
+            }
+            Err(e) => {
+                let error = format!("couldn't connect to {:?}: {}", self.target, e);
+                self.health.failed(ctx, error).await
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            if let Err(e) = connection.flush() {
+                eprintln!("Couldn't flush to {:?}, data was lost: {}", self.target, e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Actor for InfluxSink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<PerformanceIndicators>().await?;
+        self.start(ctx).await;
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Restart> for InfluxSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Restart) {
+        self.start(ctx).await;
+    }
+}
+
+#[async_trait]
+impl Handler<Drain> for InfluxSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<PerformanceIndicators> for InfluxSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        let Some(connection) = &mut self.connection else {
+            return;
+        };
+        let Some(line) = line_protocol(&msg) else {
+            eprintln!(
+                "Skipping the indicators of '{}' at {}, they don't convert to floats",
+                msg.symbol, msg.timestamp
+            );
+            return;
+        };
+        if let Err(e) = connection.send(&line) {
+            self.connection = None;
+            let error = format!("couldn't send to {:?}: {}", self.target, e);
+            self.health.failed(ctx, error).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::signal::Price;
+    use chrono::prelude::*;
+
+    fn indicators(symbol: &str) -> PerformanceIndicators {
+        PerformanceIndicators {
+            symbol: symbol.to_string(),
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            price: Price::from_usize(2),
+            pct_change: Price::zero(),
+            period_min: Price::one(),
+            period_max: Price::from_usize(3),
+            last_sma: Price::from_usize(2),
+        }
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let text = prometheus(&[indicators("AAPL"), indicators("BR\"K")]);
+        assert!(text.starts_with(
+            "# HELP stock_price Last price of the period\n# TYPE stock_price gauge\n"
+        ));
+        assert!(text.contains("stock_price{symbol=\"AAPL\"} 2\n"));
+        assert!(text.contains("stock_last_sma{symbol=\"BR\\\"K\"} 2\n"));
+        assert!(text.contains("stock_timestamp_seconds{symbol=\"AAPL\"} 1700000000\n"));
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        assert_eq!(
+            line_protocol(&indicators("BRK A")).unwrap(),
+            "indicators,symbol=BRK\\ A price=2,pct_change=0,period_min=1,period_max=3,last_sma=2 1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn test_InfluxTarget_from_str() {
+        assert_eq!(
+            "udp://localhost:8089".parse(),
+            Ok(InfluxTarget::Udp("localhost:8089".to_string()))
+        );
+        assert_eq!(
+            "tcp://localhost:8094".parse(),
+            Ok(InfluxTarget::Tcp("localhost:8094".to_string()))
+        );
+        assert_eq!(
+            "metrics.lp".parse(),
+            Ok(InfluxTarget::File(PathBuf::from("metrics.lp")))
+        );
+        assert!("".parse::<InfluxTarget>().is_err());
+    }
+
+    #[async_std::test]
+    async fn test_InfluxTarget_connect() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = InfluxTarget::Udp(server.local_addr().unwrap().to_string());
+        let mut connection = target.connect().await.unwrap();
+        connection
+            .send(&line_protocol(&indicators("AAPL")).unwrap())
+            .unwrap();
+
+        let mut buffer = [0; 256];
+        let n = server.recv(&mut buffer).unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..n]).starts_with("indicators,symbol=AAPL "));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = InfluxTarget::Tcp(listener.local_addr().unwrap().to_string());
+        assert!(matches!(
+            target.connect().await.unwrap(),
+            Connection::Tcp(_)
+        ));
+
+        // lines in a file are written without waiting for the connection to close
+        let path = std::env::temp_dir().join(format!("influx-{}.lp", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut connection = InfluxTarget::File(path.clone()).connect().await.unwrap();
+        connection
+            .send(&line_protocol(&indicators("AAPL")).unwrap())
+            .unwrap();
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("indicators,symbol=AAPL "));
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
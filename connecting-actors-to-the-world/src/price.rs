@@ -0,0 +1,139 @@
+use clap::ValueEnum;
+use yahoo_finance_api as yahoo;
+
+///
+/// The quote field a price series is built from.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PriceField {
+    Close,
+    Adjclose,
+    Open,
+    /// `(high + low + close) / 3`
+    Typical,
+}
+
+impl PriceField {
+    pub fn of(&self, quote: &yahoo::Quote) -> f64 {
+        match self {
+            PriceField::Close => quote.close,
+            PriceField::Adjclose => quote.adjclose,
+            PriceField::Open => quote.open,
+            PriceField::Typical => (quote.high + quote.low + quote.close) / 3.0,
+        }
+    }
+}
+
+///
+/// Adjustments applied to a price series. Yahoo's prices are already split-adjusted, so splits
+/// need no extra step.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Adjustment {
+    None,
+    /// Reinvest dividends on their ex-date to get a total-return series
+    TotalReturn,
+}
+
+impl Adjustment {
+    ///
+    /// Check that the adjustment can be applied to a field: Yahoo's `adjclose` already accounts for
+    /// dividends, reinvesting them again would count them twice.
+    ///
+    pub fn check(&self, field: PriceField) -> Result<(), String> {
+        if *self == Adjustment::TotalReturn && field == PriceField::Adjclose {
+            Err(
+                "adjclose already includes dividends, use total-return with another price \
+                 field like close"
+                    .to_string(),
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+///
+/// Extract the price series from quotes (sorted by time, asc) and apply the adjustment. Dividends
+/// are expected to be on the same share basis as the quotes.
+///
+pub fn price_series(
+    quotes: &[yahoo::Quote],
+    dividends: &[yahoo::Dividend],
+    field: PriceField,
+    adjustment: Adjustment,
+) -> Vec<f64> {
+    let mut factor = 1.0;
+    let mut previous: Option<&yahoo::Quote> = None;
+    quotes
+        .iter()
+        .map(|q| {
+            if let (Adjustment::TotalReturn, Some(prev)) = (adjustment, previous) {
+                let prev_price = field.of(prev);
+                if prev_price != 0.0 {
+                    for d in dividends
+                        .iter()
+                        .filter(|d| d.date > prev.timestamp && d.date <= q.timestamp)
+                    {
+                        factor *= 1.0 + d.amount / prev_price;
+                    }
+                }
+            }
+            previous = Some(q);
+            field.of(q) * factor
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_price_series() {
+        let quote = |timestamp, close| yahoo::Quote {
+            timestamp,
+            open: close - 1.0,
+            high: close + 1.0,
+            low: close - 2.0,
+            volume: 100,
+            close,
+            adjclose: close / 2.0,
+        };
+        let quotes = vec![quote(1, 10.0), quote(2, 10.0), quote(3, 12.0)];
+        let dividends = vec![yahoo::Dividend {
+            amount: 1.0,
+            date: 2,
+        }];
+
+        assert_eq!(
+            price_series(&quotes, &dividends, PriceField::Close, Adjustment::None),
+            vec![10.0, 10.0, 12.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Adjclose, Adjustment::None),
+            vec![5.0, 5.0, 6.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Typical, Adjustment::TotalReturn),
+            vec![29.0 / 3.0, 29.0 / 3.0, 35.0 / 3.0]
+        );
+        assert_eq!(
+            price_series(
+                &quotes,
+                &dividends,
+                PriceField::Close,
+                Adjustment::TotalReturn
+            ),
+            vec![10.0, 11.0, 13.200000000000001]
+        );
+    }
+
+    #[test]
+    fn test_Adjustment_check() {
+        assert!(Adjustment::TotalReturn.check(PriceField::Close).is_ok());
+        assert!(Adjustment::TotalReturn.check(PriceField::Adjclose).is_err());
+        assert!(Adjustment::None.check(PriceField::Adjclose).is_ok());
+    }
+}
@@ -0,0 +1,435 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::Serialize;
+use xactor::*;
+
+use crate::{
+    calendar::{Calendar, MarketSchedule},
+    parse_symbol, Interval, QuoteRequest,
+};
+
+///
+/// Pause requesting quotes for a symbol, or for all symbols if `None`
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct Pause(pub Option<String>);
+
+///
+/// Resume requesting quotes for a symbol, or for all symbols if `None`. Runs missed while paused
+/// are caught up on right away.
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct Resume(pub Option<String>);
+
+///
+/// Add a symbol to the watchlist and request its quotes right away.
+///
+/// # Returns
+///
+/// `false` if the symbol was already on the watchlist.
+///
+#[message(result = "bool")]
+#[derive(Debug, Clone)]
+pub struct AddSymbol(pub String);
+
+///
+/// Remove a symbol from the watchlist.
+///
+/// # Returns
+///
+/// `false` if the symbol wasn't on the watchlist.
+///
+#[message(result = "bool")]
+#[derive(Debug, Clone)]
+pub struct RemoveSymbol(pub String);
+
+///
+/// Get the symbols on the watchlist, sorted
+///
+#[message(result = "Vec<WatchedSymbol>")]
+#[derive(Debug, Clone)]
+pub struct Watchlist;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WatchedSymbol {
+    pub symbol: String,
+    pub paused: bool,
+}
+
+#[message]
+#[derive(Debug, Clone)]
+struct Tick;
+
+#[derive(Debug)]
+struct SymbolSchedule {
+    next_run: DateTime<Utc>,
+    paused: bool,
+}
+
+impl SymbolSchedule {
+    fn new(next_run: DateTime<Utc>) -> Self {
+        SymbolSchedule {
+            next_run,
+            paused: false,
+        }
+    }
+}
+
+///
+/// Read a comma-separated watchlist file, the same format as `sp500.*.txt`. Symbols are
+/// normalized, and an invalid one makes the file invalid.
+///
+pub fn read_watchlist(path: impl AsRef<Path>) -> io::Result<Vec<String>> {
+    fs::read_to_string(path)?
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            parse_symbol(s).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("'{}' isn't a valid symbol", s),
+                )
+            })
+        })
+        .collect()
+}
+
+///
+/// Write a watchlist file. The symbols go to a temporary file first, which then replaces the
+/// watchlist, so a failed write doesn't leave a truncated list behind.
+///
+fn write_watchlist<'a>(
+    path: impl AsRef<Path>,
+    symbols: impl Iterator<Item = &'a String>,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, symbols.cloned().collect::<Vec<_>>().join(","))?;
+    fs::rename(&temporary, path)
+}
+
+///
+/// Actor that publishes `QuoteRequest`s for each symbol on its own schedule. The schedules are
+/// checked every second, so runs missed while the actor was busy (or the host asleep) are caught
+/// up on with a single request.
+///
+/// With a watchlist file, the symbols are read from it (if it exists) and every change is written
+/// back, so they survive restarts. Without one, changes only survive a restart by the supervisor
+/// if they are kept in shared symbols (see `with_shared_symbols`), and are lost when the process
+/// exits.
+///
+pub struct Scheduler {
+    from: DateTime<Utc>,
+    interval: Interval,
+    period: chrono::Duration,
+    market: MarketSchedule,
+    symbols: BTreeMap<String, SymbolSchedule>,
+    watchlist: Option<PathBuf>,
+    shared: Option<Arc<Mutex<Vec<String>>>>,
+}
+
+impl Scheduler {
+    pub fn new(
+        symbols: &[String],
+        from: DateTime<Utc>,
+        interval: Interval,
+        period: Duration,
+        calendar: Option<Calendar>,
+        post_close_refresh: bool,
+        watchlist: Option<PathBuf>,
+    ) -> Self {
+        let mut watchlist = watchlist;
+        let symbols = match watchlist.as_ref().map(read_watchlist) {
+            Some(Ok(saved)) => saved,
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                // saving would overwrite the list with the default symbols
+                eprintln!(
+                    "Couldn't read the watchlist, using the default symbols without saving \
+                     them: {}",
+                    e
+                );
+                watchlist = None;
+                symbols.to_vec()
+            }
+            _ => symbols.to_vec(),
+        };
+        let now = Utc::now();
+        Scheduler {
+            from,
+            interval,
+            period: chrono::Duration::from_std(period).unwrap_or(chrono::Duration::seconds(30)),
+            market: MarketSchedule::new(calendar, post_close_refresh),
+            symbols: symbols
+                .into_iter()
+                .map(|s| (s, SymbolSchedule::new(now)))
+                .collect(),
+            watchlist,
+            shared: None,
+        }
+    }
+
+    ///
+    /// Keep the symbols in `shared` too, so the next scheduler can be created from them after a
+    /// restart, e.g. `Scheduler::new(&shared.lock().unwrap(), ...)`.
+    ///
+    pub fn with_shared_symbols(mut self, shared: Arc<Mutex<Vec<String>>>) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    fn save_watchlist(&self) {
+        if let Some(shared) = &self.shared {
+            *shared.lock().unwrap() = self.symbols.keys().cloned().collect();
+        }
+        if let Some(path) = &self.watchlist {
+            if let Err(e) = write_watchlist(path, self.symbols.keys()) {
+                eprintln!("Couldn't save the watchlist to '{}': {}", path.display(), e);
+            }
+        }
+    }
+
+    ///
+    /// Collect the symbols that are due at `now` and move their next run past `now`.
+    ///
+    fn due(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let mut due = vec![];
+        for (symbol, schedule) in self.symbols.iter_mut() {
+            if schedule.paused || schedule.next_run > now {
+                continue;
+            }
+            // skip all missed runs, one request catches up on them
+            let missed = (now - schedule.next_run).num_milliseconds()
+                / self.period.num_milliseconds().max(1);
+            schedule.next_run += self.period * (missed as i32 + 1);
+            if self.market.is_due(symbol, now) {
+                due.push(symbol.clone());
+            }
+        }
+        due
+    }
+
+    fn set_paused(&mut self, symbol: Option<String>, paused: bool) {
+        for (_, schedule) in self
+            .symbols
+            .iter_mut()
+            .filter(|(s, _)| symbol.as_ref().is_none_or(|symbol| symbol == *s))
+        {
+            schedule.paused = paused;
+        }
+    }
+}
+
+#[async_trait]
+impl Actor for Scheduler {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<Pause>().await?;
+        ctx.subscribe::<Resume>().await?;
+        ctx.send_interval(Tick, Duration::from_secs(1));
+        self.save_watchlist();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Handler<Tick> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Tick) {
+        let now = Utc::now(); // Period end for this fetch
+        for symbol in self.due(now) {
+            let request = QuoteRequest {
+                symbol,
+                from: self.from,
+                to: now,
+                interval: self.interval,
+            };
+            if let Err(e) = Broker::from_registry().await.unwrap().publish(request) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<Pause> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Pause) {
+        self.set_paused(msg.0, true);
+    }
+}
+
+#[async_trait]
+impl Handler<Resume> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Resume) {
+        self.set_paused(msg.0, false);
+    }
+}
+
+#[async_trait]
+impl Handler<AddSymbol> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: AddSymbol) -> bool {
+        if self.symbols.contains_key(&msg.0) {
+            return false;
+        }
+        self.symbols.insert(msg.0, SymbolSchedule::new(Utc::now()));
+        self.save_watchlist();
+        true
+    }
+}
+
+#[async_trait]
+impl Handler<RemoveSymbol> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: RemoveSymbol) -> bool {
+        let removed = self.symbols.remove(&msg.0).is_some();
+        if removed {
+            self.save_watchlist();
+        }
+        removed
+    }
+}
+
+#[async_trait]
+impl Handler<Watchlist> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Watchlist) -> Vec<WatchedSymbol> {
+        self.symbols
+            .iter()
+            .map(|(symbol, schedule)| WatchedSymbol {
+                symbol: symbol.clone(),
+                paused: schedule.paused,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_Scheduler_due() {
+        let symbols = vec!["AAPL".to_string(), "MSFT".to_string()];
+        let mut scheduler = Scheduler::new(
+            &symbols,
+            Utc::now(),
+            Interval::OneDay,
+            Duration::from_secs(30),
+            None,
+            false,
+            None,
+        );
+        let start = scheduler.symbols["AAPL"].next_run;
+
+        assert_eq!(scheduler.due(start), symbols);
+        assert!(scheduler
+            .due(start + chrono::Duration::seconds(29))
+            .is_empty());
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(30)),
+            symbols
+        );
+
+        // three missed runs are caught up on with one request
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(125)),
+            symbols
+        );
+        assert!(scheduler
+            .due(start + chrono::Duration::seconds(140))
+            .is_empty());
+        assert_eq!(
+            scheduler.symbols["AAPL"].next_run,
+            start + chrono::Duration::seconds(150)
+        );
+
+        scheduler.set_paused(Some("AAPL".to_string()), true);
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(150)),
+            vec!["MSFT".to_string()]
+        );
+        scheduler.set_paused(None, false);
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(170)),
+            vec!["AAPL".to_string()]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_Scheduler_watchlist() {
+        let path = std::env::temp_dir().join(format!("watchlist-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let new_scheduler = || {
+            Scheduler::new(
+                &["AAPL".to_string()],
+                Utc::now(),
+                Interval::OneDay,
+                Duration::from_secs(30),
+                None,
+                false,
+                Some(path.clone()),
+            )
+        };
+
+        let addr = new_scheduler().start().await.unwrap();
+        assert_eq!(read_watchlist(&path).unwrap(), vec!["AAPL"]);
+        assert!(addr.call(AddSymbol("MSFT".to_string())).await.unwrap());
+        assert!(!addr.call(AddSymbol("MSFT".to_string())).await.unwrap());
+        assert!(addr.call(RemoveSymbol("AAPL".to_string())).await.unwrap());
+        assert!(!addr.call(RemoveSymbol("AAPL".to_string())).await.unwrap());
+        assert_eq!(read_watchlist(&path).unwrap(), vec!["MSFT"]);
+
+        // a restarted scheduler picks up the saved watchlist
+        let scheduler = new_scheduler();
+        assert_eq!(
+            scheduler.symbols.keys().collect::<Vec<_>>(),
+            vec![&"MSFT".to_string()]
+        );
+
+        // an unreadable watchlist isn't overwritten
+        fs::write(&path, "MSFT,<SCRIPT>").unwrap();
+        assert!(read_watchlist(&path).is_err());
+        let addr = new_scheduler().start().await.unwrap();
+        assert!(addr.call(AddSymbol("UBER".to_string())).await.unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "MSFT,<SCRIPT>");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_Scheduler_with_shared_symbols() {
+        let shared = Arc::new(Mutex::new(vec!["AAPL".to_string()]));
+        let new_scheduler = || {
+            let symbols = shared.lock().unwrap().clone();
+            Scheduler::new(
+                &symbols,
+                Utc::now(),
+                Interval::OneDay,
+                Duration::from_secs(30),
+                None,
+                false,
+                None,
+            )
+            .with_shared_symbols(shared.clone())
+        };
+
+        let addr = new_scheduler().start().await.unwrap();
+        assert!(addr.call(AddSymbol("MSFT".to_string())).await.unwrap());
+        assert!(addr.call(RemoveSymbol("AAPL".to_string())).await.unwrap());
+        assert_eq!(*shared.lock().unwrap(), vec!["MSFT"]);
+
+        // a restarted scheduler keeps the changes without a watchlist file
+        let scheduler = new_scheduler();
+        assert_eq!(
+            scheduler.symbols.keys().collect::<Vec<_>>(),
+            vec![&"MSFT".to_string()]
+        );
+    }
+}
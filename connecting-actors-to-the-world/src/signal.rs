@@ -1,10 +1,103 @@
+use std::{
+    iter::Sum,
+    ops::{Add, Div, Mul, Sub},
+};
+
 use async_trait::async_trait;
+use rust_decimal::prelude::{Decimal, FromPrimitive, ToPrimitive};
+
+///
+/// The numeric type used for prices and signals. Enable the `decimal` feature for exact fixed-point
+/// arithmetic, e.g. to reconcile with accounting systems.
+///
+#[cfg(not(feature = "decimal"))]
+pub type Price = f64;
+#[cfg(feature = "decimal")]
+pub type Price = Decimal;
+
+///
+/// The numeric operations a signal needs to work on a series. Implemented for `f64` and for the
+/// fixed-point `Decimal`, which keeps the results exact.
+///
+pub trait Numeric:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Sum
+    + Send
+    + Sync
+    + 'static
+{
+    fn zero() -> Self;
+
+    fn one() -> Self;
+
+    fn from_usize(n: usize) -> Self;
+
+    ///
+    /// Convert from an `f64`, `None` if the value can't be represented (e.g. `NaN`).
+    ///
+    fn from_f64(value: f64) -> Option<Self>;
+
+    ///
+    /// Convert to the nearest `f64`, e.g. for a floating point metric. `None` if there is none,
+    /// rather than a made-up value.
+    ///
+    fn to_f64(self) -> Option<f64>;
+}
+
+impl Numeric for f64 {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn one() -> Self {
+        1.0
+    }
+
+    fn from_usize(n: usize) -> Self {
+        n as f64
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        Some(value)
+    }
+
+    fn to_f64(self) -> Option<f64> {
+        Some(self)
+    }
+}
+
+impl Numeric for Decimal {
+    fn zero() -> Self {
+        Decimal::ZERO
+    }
+
+    fn one() -> Self {
+        Decimal::ONE
+    }
+
+    fn from_usize(n: usize) -> Self {
+        Decimal::from(n)
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        <Decimal as FromPrimitive>::from_f64(value)
+    }
+
+    fn to_f64(self) -> Option<f64> {
+        <Decimal as ToPrimitive>::to_f64(&self)
+    }
+}
 
 ///
 /// A trait to provide a common interface for all signal calculations.
 ///
 #[async_trait]
-pub trait AsyncStockSignal {
+pub trait AsyncStockSignal<T: Numeric = f64> {
     ///
     /// The signal's data type.
     ///
@@ -17,28 +110,28 @@ pub trait AsyncStockSignal {
     ///
     /// The signal (using the provided type) or `None` on error/invalid data.
     ///
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType>;
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType>;
 }
 
 ///
-/// Calculates the absolute and relative difference between the beginning and ending of an f64 series.
+/// Calculates the absolute and relative difference between the beginning and ending of a series.
 /// The relative difference is relative to the beginning.
 ///
 pub struct PriceDifference {}
 
 #[async_trait]
-impl AsyncStockSignal for PriceDifference {
+impl<T: Numeric> AsyncStockSignal<T> for PriceDifference {
     ///
     /// A tuple `(absolute, relative)` to represent a price difference.
     ///
-    type SignalType = (f64, f64);
+    type SignalType = (T, T);
 
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         if !series.is_empty() {
             // unwrap is safe here even if first == last
-            let (first, last) = (series.first().unwrap(), series.last().unwrap());
+            let (first, last) = (*series.first().unwrap(), *series.last().unwrap());
             let abs_diff = last - first;
-            let first = if *first == 0.0 { 1.0 } else { *first };
+            let first = if first == T::zero() { T::one() } else { first };
             let rel_diff = abs_diff / first;
             Some((abs_diff, rel_diff))
         } else {
@@ -55,15 +148,15 @@ pub struct WindowedSMA {
 }
 
 #[async_trait]
-impl AsyncStockSignal for WindowedSMA {
-    type SignalType = Vec<f64>;
+impl<T: Numeric> AsyncStockSignal<T> for WindowedSMA {
+    type SignalType = Vec<T>;
 
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         if !series.is_empty() && self.window_size > 1 {
             Some(
                 series
                     .windows(self.window_size)
-                    .map(|w| w.iter().sum::<f64>() / w.len() as f64)
+                    .map(|w| w.iter().copied().sum::<T>() / T::from_usize(w.len()))
                     .collect(),
             )
         } else {
@@ -73,38 +166,36 @@ impl AsyncStockSignal for WindowedSMA {
 }
 
 ///
-/// Find the maximum in a series of f64
+/// Find the maximum in a series
 ///
 pub struct MaxPrice {}
 
 #[async_trait]
-impl AsyncStockSignal for MaxPrice {
-    type SignalType = f64;
+impl<T: Numeric> AsyncStockSignal<T> for MaxPrice {
+    type SignalType = T;
 
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
-        if series.is_empty() {
-            None
-        } else {
-            Some(series.iter().fold(f64::MIN, |acc, q| acc.max(*q)))
-        }
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
+        series
+            .iter()
+            .copied()
+            .reduce(|acc, q| if q > acc { q } else { acc })
     }
 }
 
 ///
-/// Find the maximum in a series of f64
+/// Find the minimum in a series
 ///
 pub struct MinPrice {}
 
 #[async_trait]
-impl AsyncStockSignal for MinPrice {
-    type SignalType = f64;
+impl<T: Numeric> AsyncStockSignal<T> for MinPrice {
+    type SignalType = T;
 
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
-        if series.is_empty() {
-            None
-        } else {
-            Some(series.iter().fold(f64::MAX, |acc, q| acc.min(*q)))
-        }
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
+        series
+            .iter()
+            .copied()
+            .reduce(|acc, q| if q < acc { q } else { acc })
     }
 }
 
@@ -112,11 +203,12 @@ impl AsyncStockSignal for MinPrice {
 mod tests {
     #![allow(non_snake_case)]
     use super::*;
+    use rust_decimal_macros::dec;
 
     #[async_std::test]
     async fn test_PriceDifference_calculate() {
         let signal = PriceDifference {};
-        assert_eq!(signal.calculate(&[]).await, None);
+        assert_eq!(signal.calculate(&[] as &[f64]).await, None);
         assert_eq!(signal.calculate(&[1.0]).await, Some((0.0, 0.0)));
         assert_eq!(signal.calculate(&[1.0, 0.0]).await, Some((-1.0, -1.0)));
         assert_eq!(
@@ -134,7 +226,7 @@ mod tests {
     #[async_std::test]
     async fn test_MinPrice_calculate() {
         let signal = MinPrice {};
-        assert_eq!(signal.calculate(&[]).await, None);
+        assert_eq!(signal.calculate(&[] as &[f64]).await, None);
         assert_eq!(signal.calculate(&[1.0]).await, Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]).await, Some(0.0));
         assert_eq!(
@@ -152,7 +244,7 @@ mod tests {
     #[async_std::test]
     async fn test_MaxPrice_calculate() {
         let signal = MaxPrice {};
-        assert_eq!(signal.calculate(&[]).await, None);
+        assert_eq!(signal.calculate(&[] as &[f64]).await, None);
         assert_eq!(signal.calculate(&[1.0]).await, Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]).await, Some(1.0));
         assert_eq!(
@@ -183,4 +275,26 @@ mod tests {
         let signal = WindowedSMA { window_size: 10 };
         assert_eq!(signal.calculate(&series).await, Some(vec![]));
     }
+
+    #[async_std::test]
+    async fn test_signals_calculate_Decimal() {
+        let series = vec![dec!(2.0), dec!(4.5), dec!(5.3), dec!(6.5), dec!(4.7)];
+
+        let signal = WindowedSMA { window_size: 3 };
+        assert_eq!(
+            signal.calculate(&series).await,
+            Some(vec![
+                dec!(3.9333333333333333333333333333),
+                dec!(5.4333333333333333333333333333),
+                dec!(5.5)
+            ])
+        );
+        assert_eq!(
+            PriceDifference {}.calculate(&series).await,
+            Some((dec!(2.7), dec!(1.35)))
+        );
+        assert_eq!(MinPrice {}.calculate(&series).await, Some(dec!(2.0)));
+        assert_eq!(MaxPrice {}.calculate(&series).await, Some(dec!(6.5)));
+        assert_eq!(Numeric::to_f64(dec!(1.25)), Some(1.25));
+    }
 }
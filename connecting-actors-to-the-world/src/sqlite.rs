@@ -0,0 +1,343 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use rusqlite::{params, types::Value, Connection};
+use xactor::*;
+
+use crate::{
+    health::{Health, Restart},
+    shutdown::Drain,
+    signal::Price,
+    PerformanceIndicators, Quotes,
+};
+
+///
+/// Schema migrations in `migrations/`, applied in order. `PRAGMA user_version` keeps track of how
+/// many ran, so only append to this list.
+///
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_indicators_and_quotes.sql"),
+    include_str!("../migrations/0002_untyped_indicator_prices.sql"),
+];
+
+///
+/// Bring a database's schema up to date.
+///
+pub fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let tx = connection.transaction()?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+    }
+    tx.commit()
+}
+
+///
+/// A price as it's stored: a REAL, or with the `decimal` feature the exact decimal as TEXT.
+///
+#[cfg(not(feature = "decimal"))]
+fn sql(value: Price) -> Value {
+    Value::Real(value)
+}
+
+#[cfg(feature = "decimal")]
+fn sql(value: Price) -> Value {
+    Value::Text(value.to_string())
+}
+
+///
+/// Store performance indicators. The same period is published again until new quotes come in, so
+/// a record replaces an earlier one of the same symbol and timestamp.
+///
+fn insert_indicators(connection: &Connection, msg: &PerformanceIndicators) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT OR REPLACE INTO indicators
+            (symbol, timestamp, price, pct_change, period_min, period_max, last_sma)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            msg.symbol,
+            msg.timestamp,
+            sql(msg.price),
+            sql(msg.pct_change),
+            sql(msg.period_min),
+            sql(msg.period_max),
+            sql(msg.last_sma),
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_quotes(connection: &mut Connection, msg: &Quotes) -> rusqlite::Result<()> {
+    let tx = connection.transaction()?;
+    {
+        let mut insert = tx.prepare_cached(
+            "INSERT OR REPLACE INTO quotes
+                (symbol, timestamp, open, high, low, close, adjclose, volume)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for quote in &msg.quotes {
+            let timestamp = Utc.timestamp_opt(quote.timestamp as i64, 0).unwrap();
+            insert.execute(params![
+                msg.symbol,
+                timestamp,
+                quote.open,
+                quote.high,
+                quote.low,
+                quote.close,
+                quote.adjclose,
+                quote.volume as i64,
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+///
+/// Actor for storing incoming messages in a SQLite database, to query the history with SQL.
+/// Timestamps are stored as UTC text (e.g. `2024-01-31 21:00:00+00:00`), which sorts in time order
+/// and works with SQLite's date functions.
+///
+/// If the database fails to open or write, it's restarted with a backoff and drops messages until
+/// then, like `FileSink`.
+///
+#[derive(Default, Debug)]
+pub struct SqliteSink {
+    pub path: PathBuf,
+    /// Also store the raw quotes each indicator is calculated from
+    pub quotes: bool,
+    pub connection: Option<Connection>,
+    pub health: Health,
+}
+
+impl SqliteSink {
+    ///
+    /// Open the database, or restart later if that fails.
+    ///
+    async fn start(&mut self, ctx: &mut Context<Self>) {
+        let opened = Connection::open(&self.path).and_then(|mut connection| {
+            migrate(&mut connection)?;
+            Ok(connection)
+        });
+        match opened {
+            Ok(connection) => {
+                self.connection = Some(connection);
+                self.health.up().await;
+            }
+            Err(e) => {
+                self.health
+                    .failed(
+                        ctx,
+                        format!("couldn't open '{}': {}", self.path.display(), e),
+                    )
+                    .await
+            }
+        }
+    }
+
+    ///
+    /// Close the database and restart later.
+    ///
+    async fn failed(&mut self, ctx: &mut Context<Self>, error: String) {
+        self.close();
+        self.health.failed(ctx, error).await;
+    }
+
+    fn close(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if let Err((_, e)) = connection.close() {
+                eprintln!("Couldn't close '{}': {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Actor for SqliteSink {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        if self.quotes {
+            ctx.subscribe::<Quotes>().await?;
+        }
+        ctx.subscribe::<PerformanceIndicators>().await?;
+        self.start(ctx).await;
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Restart> for SqliteSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, _msg: Restart) {
+        self.start(ctx).await;
+    }
+}
+
+#[async_trait]
+impl Handler<Drain> for SqliteSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<PerformanceIndicators> for SqliteSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        let Some(connection) = &self.connection else {
+            return;
+        };
+        if let Err(e) = insert_indicators(connection, &msg) {
+            let error = format!("couldn't store indicators of '{}': {}", msg.symbol, e);
+            self.failed(ctx, error).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<Quotes> for SqliteSink {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: Quotes) {
+        let Some(connection) = &mut self.connection else {
+            return;
+        };
+        if let Err(e) = insert_quotes(connection, &msg) {
+            let error = format!("couldn't store quotes of '{}': {}", msg.symbol, e);
+            self.failed(ctx, error).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::{
+        health::{Backoff, HealthMonitor, HealthRequest, SinkHealth, Status},
+        signal::Numeric,
+    };
+    use std::time::Duration;
+    use yahoo_finance_api as yahoo;
+
+    #[test]
+    fn test_migrate() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        // running it again is a no-op
+        migrate(&mut connection).unwrap();
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        // the indicators of a database from before the untyped prices are kept
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(MIGRATIONS[0]).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        connection
+            .execute(
+                "INSERT INTO indicators \
+                 VALUES ('AAPL', '2023-11-14 22:13:20+00:00', 2, 0, 1, 2, 1)",
+                [],
+            )
+            .unwrap();
+        migrate(&mut connection).unwrap();
+        let price: f64 = connection
+            .query_row("SELECT price FROM indicators", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(price, 2.0);
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        let indicators = |price: usize| PerformanceIndicators {
+            symbol: "AAPL".to_string(),
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            price: Price::from_usize(price),
+            pct_change: Price::zero(),
+            period_min: Price::one(),
+            period_max: Price::from_usize(price),
+            last_sma: Price::one(),
+        };
+        insert_indicators(&connection, &indicators(2)).unwrap();
+        insert_indicators(&connection, &indicators(3)).unwrap();
+        let (count, price, timestamp): (usize, f64, String) = connection
+            .query_row(
+                "SELECT COUNT(*), MAX(CAST(price AS REAL)), MAX(timestamp) FROM indicators
+                    WHERE symbol = 'AAPL'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((count, price), (1, 3.0));
+        assert_eq!(timestamp, "2023-11-14 22:13:20+00:00");
+
+        // prices are stored as they are, exact with the `decimal` feature
+        let third = Price::one() / Price::from_usize(3);
+        let mut exact = indicators(1);
+        exact.last_sma = third;
+        insert_indicators(&connection, &exact).unwrap();
+        let last_sma: Value = connection
+            .query_row("SELECT last_sma FROM indicators", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(last_sma, sql(third));
+
+        let quote = |timestamp| yahoo::Quote {
+            timestamp,
+            open: 1.0,
+            high: 2.0,
+            low: 0.5,
+            volume: 100,
+            close: 1.5,
+            adjclose: 1.5,
+        };
+        let quotes = Quotes {
+            symbol: "AAPL".to_string(),
+            quotes: vec![quote(1_700_000_000), quote(1_700_086_400)],
+            dividends: vec![],
+        };
+        insert_quotes(&mut connection, &quotes).unwrap();
+        insert_quotes(&mut connection, &quotes).unwrap();
+        let count: usize = connection
+            .query_row("SELECT COUNT(*) FROM quotes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[async_std::test]
+    async fn test_SqliteSink_health() {
+        let monitor = HealthMonitor::new(Utc::now()).start().await.unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("missing-{}", std::process::id()))
+            .join("db.sqlite");
+        let _sink = SqliteSink {
+            path,
+            health: Health::new(
+                "SqliteSink",
+                Backoff {
+                    max_restarts: 1,
+                    initial: Duration::from_secs(60),
+                    ..Default::default()
+                },
+            ),
+            ..Default::default()
+        }
+        .start()
+        .await
+        .unwrap();
+
+        Broker::<SinkHealth>::from_registry()
+            .await
+            .unwrap()
+            .call(Drain)
+            .await
+            .unwrap();
+        let health = monitor.call(HealthRequest).await.unwrap();
+        let health = health.iter().find(|h| h.sink == "SqliteSink").unwrap();
+        assert_eq!(health.status, Status::Degraded);
+        assert!(health.error.as_ref().unwrap().starts_with("couldn't open"));
+    }
+}
@@ -0,0 +1,379 @@
+use std::{collections::BTreeSet, sync::Mutex};
+
+use async_std::channel::Sender;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use xactor::*;
+
+use crate::{
+    auth::Scope,
+    metrics::{LatestRequest, LatestSink},
+    parse_symbol,
+    scheduler::{AddSymbol, Scheduler},
+    PerformanceIndicators, Quotes,
+};
+
+///
+/// A command from a WebSocket client, e.g. `{"type": "subscribe", "symbols": ["AAPL"]}`
+///
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Receive the updates of `symbols`, or of all symbols if empty
+    Subscribe {
+        #[serde(default)]
+        symbols: Vec<String>,
+    },
+    /// Stop receiving the updates of `symbols`. Without symbols, all subscriptions end, including
+    /// the one to all symbols.
+    Unsubscribe {
+        #[serde(default)]
+        symbols: Vec<String>,
+    },
+    /// Get the latest indicators of `symbols`, or of the subscribed ones if empty
+    Snapshot {
+        #[serde(default)]
+        symbols: Vec<String>,
+    },
+    /// Add a symbol to the watchlist, needs the admin scope
+    AddSymbol { symbol: String },
+}
+
+///
+/// A quote as sent to WebSocket clients
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct QuoteUpdate {
+    pub timestamp: DateTime<Utc>,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub adjclose: f64,
+    pub volume: u64,
+}
+
+///
+/// A message to a WebSocket client, e.g. `{"type": "indicators", "symbol": "AAPL", ...}`
+///
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    Indicators(PerformanceIndicators),
+    Quotes {
+        symbol: String,
+        quotes: Vec<QuoteUpdate>,
+    },
+    Snapshot {
+        indicators: Vec<PerformanceIndicators>,
+    },
+    /// The subscriptions after a `subscribe` or `unsubscribe`
+    Subscribed {
+        all: bool,
+        symbols: Vec<String>,
+    },
+    /// The result of an `add_symbol`, `added` is false if it was already watched
+    SymbolAdded {
+        symbol: String,
+        added: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+///
+/// A text message received from the client
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct Received(pub String);
+
+///
+/// Actor bridging a WebSocket client to the broker: it handles the client's commands and queues
+/// the updates it subscribed to. When the client falls too far behind to keep up with the queue,
+/// the session stops, which closes the connection instead of stalling the rest of the system.
+///
+pub struct ClientSession {
+    latest: Addr<LatestSink>,
+    scheduler: Addr<Scheduler>,
+    /// The scope of the client's token
+    scope: Scope,
+    sender: Sender<Update>,
+    /// Subscribed to all symbols
+    all: bool,
+    symbols: BTreeSet<String>,
+}
+
+impl ClientSession {
+    pub fn new(
+        latest: Addr<LatestSink>,
+        scheduler: Addr<Scheduler>,
+        scope: Scope,
+        sender: Sender<Update>,
+    ) -> Self {
+        ClientSession {
+            latest,
+            scheduler,
+            scope,
+            sender,
+            all: false,
+            symbols: BTreeSet::new(),
+        }
+    }
+
+    fn wants(&self, symbol: &str) -> bool {
+        self.all || self.symbols.contains(symbol)
+    }
+
+    fn push(&mut self, ctx: &mut Context<Self>, update: Update) {
+        if self.sender.try_send(update).is_err() {
+            eprintln!("WebSocket client fell behind or left, closing its session");
+            ctx.stop(None);
+        }
+    }
+
+    fn subscribed(&self) -> Update {
+        Update::Subscribed {
+            all: self.all,
+            symbols: self.symbols.iter().cloned().collect(),
+        }
+    }
+
+    async fn execute(&mut self, command: Command) -> Result<Update> {
+        Ok(match command {
+            Command::Subscribe { symbols } if symbols.is_empty() => {
+                self.all = true;
+                self.subscribed()
+            }
+            Command::Subscribe { symbols } => {
+                self.symbols
+                    .extend(symbols.iter().map(|s| s.trim().to_uppercase()));
+                self.subscribed()
+            }
+            Command::Unsubscribe { symbols } if symbols.is_empty() => {
+                self.all = false;
+                self.symbols.clear();
+                self.subscribed()
+            }
+            Command::Unsubscribe { symbols } => {
+                for symbol in symbols {
+                    self.symbols.remove(&symbol.trim().to_uppercase());
+                }
+                self.subscribed()
+            }
+            Command::Snapshot { symbols } => {
+                let symbols: BTreeSet<String> =
+                    symbols.iter().map(|s| s.trim().to_uppercase()).collect();
+                let mut indicators = self.latest.call(LatestRequest).await?;
+                if !symbols.is_empty() {
+                    indicators.retain(|i| symbols.contains(&i.symbol));
+                } else if !self.all {
+                    indicators.retain(|i| self.symbols.contains(&i.symbol));
+                }
+                Update::Snapshot { indicators }
+            }
+            Command::AddSymbol { .. } if self.scope < Scope::Admin => Update::Error {
+                message: "adding symbols needs an admin token".to_string(),
+            },
+            Command::AddSymbol { symbol } => match parse_symbol(&symbol) {
+                Some(symbol) => {
+                    let added = self.scheduler.call(AddSymbol(symbol.clone())).await?;
+                    Update::SymbolAdded { symbol, added }
+                }
+                None => Update::Error {
+                    message: format!("invalid symbol '{}'", symbol),
+                },
+            },
+        })
+    }
+}
+
+///
+/// The sessions subscribed to the brokers, by actor id. The brokers don't drop subscribers on their
+/// own, so each session has to unsubscribe when it stops.
+///
+static SUBSCRIBED: Mutex<BTreeSet<ActorId>> = Mutex::new(BTreeSet::new());
+
+#[async_trait]
+impl Actor for ClientSession {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<PerformanceIndicators>().await?;
+        ctx.subscribe::<Quotes>().await?;
+        SUBSCRIBED.lock().unwrap().insert(ctx.actor_id());
+        Ok(())
+    }
+
+    async fn stopped(&mut self, ctx: &mut Context<Self>) {
+        if let Err(e) = ctx.unsubscribe::<PerformanceIndicators>().await {
+            eprintln!("{}", e);
+        }
+        if let Err(e) = ctx.unsubscribe::<Quotes>().await {
+            eprintln!("{}", e);
+        }
+        SUBSCRIBED.lock().unwrap().remove(&ctx.actor_id());
+    }
+}
+
+#[async_trait]
+impl Handler<Received> for ClientSession {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: Received) {
+        let update = match serde_json::from_str(&msg.0) {
+            Ok(command) => self
+                .execute(command)
+                .await
+                .unwrap_or_else(|e| Update::Error {
+                    message: e.to_string(),
+                }),
+            Err(e) => Update::Error {
+                message: format!("invalid command: {}", e),
+            },
+        };
+        self.push(ctx, update);
+    }
+}
+
+#[async_trait]
+impl Handler<PerformanceIndicators> for ClientSession {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: PerformanceIndicators) {
+        if self.wants(&msg.symbol) {
+            self.push(ctx, Update::Indicators(msg));
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<Quotes> for ClientSession {
+    async fn handle(&mut self, ctx: &mut Context<Self>, msg: Quotes) {
+        if self.wants(&msg.symbol) {
+            let quotes = msg
+                .quotes
+                .iter()
+                .map(|q| QuoteUpdate {
+                    timestamp: DateTime::from_timestamp(q.timestamp as i64, 0).unwrap_or_default(),
+                    open: q.open,
+                    high: q.high,
+                    low: q.low,
+                    close: q.close,
+                    adjclose: q.adjclose,
+                    volume: q.volume,
+                })
+                .collect();
+            self.push(
+                ctx,
+                Update::Quotes {
+                    symbol: msg.symbol,
+                    quotes,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use crate::{
+        signal::{Numeric, Price},
+        Interval,
+    };
+    use std::time::Duration;
+
+    fn indicators(symbol: &str) -> PerformanceIndicators {
+        PerformanceIndicators {
+            symbol: symbol.to_string(),
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            price: Price::one(),
+            pct_change: Price::zero(),
+            period_min: Price::one(),
+            period_max: Price::one(),
+            last_sma: Price::one(),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_ClientSession() {
+        let latest = LatestSink::default().start().await.unwrap();
+        let scheduler = Scheduler::new(
+            &[],
+            Utc::now(),
+            Interval::default(),
+            Duration::from_secs(30),
+            None,
+            false,
+            None,
+        )
+        .start()
+        .await
+        .unwrap();
+        let (sender, receiver) = async_std::channel::bounded(3);
+        let session = ClientSession::new(latest, scheduler, Scope::Admin, sender)
+            .start()
+            .await
+            .unwrap();
+
+        let command = r#"{"type": "subscribe", "symbols": ["uber"]}"#;
+        session.send(Received(command.to_string())).unwrap();
+        session.send(indicators("MSFT")).unwrap();
+        session.send(indicators("UBER")).unwrap();
+        let update = receiver.recv().await.unwrap();
+        assert!(
+            matches!(update, Update::Subscribed { all: false, symbols } if symbols == ["UBER"])
+        );
+        let update = receiver.recv().await.unwrap();
+        assert!(matches!(update, Update::Indicators(i) if i.symbol == "UBER"));
+
+        session.send(Received("nonsense".to_string())).unwrap();
+        let update = receiver.recv().await.unwrap();
+        assert!(matches!(update, Update::Error { .. }));
+        let command = r#"{"type": "add_symbol", "symbol": "msft"}"#;
+        session.send(Received(command.to_string())).unwrap();
+        let update = receiver.recv().await.unwrap();
+        assert!(matches!(update, Update::SymbolAdded { symbol, added: true } if symbol == "MSFT"));
+
+        // a client that doesn't keep up is disconnected
+        for _ in 0..4 {
+            let _ = session.send(indicators("UBER"));
+        }
+        let id = session.actor_id();
+        session.wait_for_stop().await;
+        let mut queued = 0;
+        while receiver.recv().await.is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, 3);
+        assert!(!SUBSCRIBED.lock().unwrap().contains(&id));
+    }
+
+    #[async_std::test]
+    async fn test_ClientSession_stopped() {
+        let latest = LatestSink::default().start().await.unwrap();
+        let scheduler = Scheduler::new(
+            &[],
+            Utc::now(),
+            Interval::default(),
+            Duration::from_secs(30),
+            None,
+            false,
+            None,
+        )
+        .start()
+        .await
+        .unwrap();
+        let (sender, receiver) = async_std::channel::bounded(1);
+        let mut session = ClientSession::new(latest, scheduler, Scope::Read, sender)
+            .start()
+            .await
+            .unwrap();
+        let id = session.actor_id();
+        assert!(SUBSCRIBED.lock().unwrap().contains(&id));
+
+        // the client disconnects
+        drop(receiver);
+        session.stop(None).unwrap();
+        session.wait_for_stop().await;
+        assert!(!SUBSCRIBED.lock().unwrap().contains(&id));
+    }
+}
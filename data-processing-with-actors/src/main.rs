@@ -1,16 +1,28 @@
 use std::{
-    fs::File,
-    io::{BufWriter, Write},
+    iter::Sum,
+    ops::{Add, Div, Sub},
     time::Duration,
 };
 
-use async_std::{prelude::*, stream};
+use async_std::{future, io, prelude::*, task};
 use async_trait::async_trait;
 use chrono::prelude::*;
-use clap::Parser;
-use xactor::{message, Actor, Broker, Context, Handler, Result, Service, Supervisor};
+use clap::{Parser, ValueEnum};
+use rust_decimal::Decimal;
+use xactor::{message, Actor, Addr, Broker, Context, Error, Handler, Result, Service, Supervisor};
 use yahoo_finance_api as yahoo;
 
+mod calendar;
+mod csv_format;
+mod output;
+mod scheduler;
+mod shutdown;
+use calendar::Calendar;
+use csv_format::{parse_delimiter, CsvFormat};
+use output::{FlushPolicy, OutputOptions, RotatingFiles, Rotation};
+use scheduler::{Pause, Resume, Scheduler};
+use shutdown::Drain;
+
 #[derive(Parser, Debug)]
 #[clap(
     version = "1.0",
@@ -22,13 +34,134 @@ struct Opts {
     symbols: String,
     #[clap(short, long)]
     from: String,
+    /// The quote field to calculate the signals on, `close` with `--adjust total-return`
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = PriceField::Adjclose,
+        default_value_if("adjust", "total-return", "close")
+    )]
+    price_field: PriceField,
+    /// Adjustment applied to the price series
+    #[clap(long, value_enum, default_value_t = Adjustment::None)]
+    adjust: Adjustment,
+    /// Exchange calendar file (e.g. calendar.toml) to only request quotes while markets are open
+    #[clap(long)]
+    calendar: Option<String>,
+    /// Request quotes once more after a market closed
+    #[clap(long)]
+    post_close_refresh: bool,
+    /// Field delimiter of the CSV output, a single character or `tab`
+    #[clap(long, value_parser = parse_delimiter, default_value = ",")]
+    delimiter: u8,
+    /// Decimals of the numbers in the CSV output, all of them by default. A raw `pct_change` is
+    /// never rounded
+    #[clap(long)]
+    precision: Option<usize>,
+    /// Write `$` prices and `%` changes rounded to 2 decimals instead of raw numbers
+    #[clap(long)]
+    pretty: bool,
+    /// Output file. `{date}` is replaced with the date, `{symbol}` with the symbol for one file
+    /// per symbol and `{timestamp}` with the start time
+    #[clap(short, long, default_value = "output.csv")]
+    output: String,
+    /// Append to existing output files instead of truncating them
+    #[clap(long)]
+    append: bool,
+    /// Start a new output file `daily` or once it reached a size like `100M`
+    #[clap(long)]
+    rotate: Option<Rotation>,
+    /// Compress rotated output files with gzip
+    #[clap(long)]
+    gzip: bool,
+    /// Flush the output file every `N` records, every `Ns` seconds or only on `shutdown`
+    #[clap(long, default_value = "5s")]
+    flush: FlushPolicy,
+    /// Sync the output file to disk whenever it's flushed
+    #[clap(long)]
+    fsync: bool,
+    /// Seconds to wait for downloads and sinks to finish on Ctrl-C/SIGTERM
+    #[clap(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+}
+
+///
+/// The numeric type used for prices and signals. Enable the `decimal` feature for exact fixed-point
+/// arithmetic, e.g. to reconcile with accounting systems.
+///
+#[cfg(not(feature = "decimal"))]
+type Price = f64;
+#[cfg(feature = "decimal")]
+type Price = Decimal;
+
+///
+/// The numeric operations a signal needs to work on a series. Implemented for `f64` and for the
+/// fixed-point `Decimal`, which keeps the results exact.
+///
+trait Numeric:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Div<Output = Self>
+    + Sum
+    + Send
+    + Sync
+    + 'static
+{
+    fn zero() -> Self;
+
+    fn one() -> Self;
+
+    fn from_usize(n: usize) -> Self;
+
+    ///
+    /// Convert from an `f64`, `None` if the value can't be represented (e.g. `NaN`).
+    ///
+    fn from_f64(value: f64) -> Option<Self>;
+}
+
+impl Numeric for f64 {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn one() -> Self {
+        1.0
+    }
+
+    fn from_usize(n: usize) -> Self {
+        n as f64
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl Numeric for Decimal {
+    fn zero() -> Self {
+        Decimal::ZERO
+    }
+
+    fn one() -> Self {
+        Decimal::ONE
+    }
+
+    fn from_usize(n: usize) -> Self {
+        Decimal::from(n)
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        <Decimal as rust_decimal::prelude::FromPrimitive>::from_f64(value)
+    }
 }
 
 ///
 /// A trait to provide a common interface for all signal calculations.
 ///
 #[async_trait]
-trait AsyncStockSignal {
+trait AsyncStockSignal<T: Numeric = f64> {
     ///
     /// The signal's data type.
     ///
@@ -41,28 +174,28 @@ trait AsyncStockSignal {
     ///
     /// The signal (using the provided type) or `None` on error/invalid data.
     ///
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType>;
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType>;
 }
 
 ///
-/// Calculates the absolute and relative difference between the beginning and ending of an f64 series.
+/// Calculates the absolute and relative difference between the beginning and ending of a series.
 /// The relative difference is relative to the beginning.
 ///
 struct PriceDifference {}
 
 #[async_trait]
-impl AsyncStockSignal for PriceDifference {
+impl<T: Numeric> AsyncStockSignal<T> for PriceDifference {
     ///
     /// A tuple `(absolute, relative)` to represent a price difference.
     ///
-    type SignalType = (f64, f64);
+    type SignalType = (T, T);
 
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         if !series.is_empty() {
             // unwrap is safe here even if first == last
-            let (first, last) = (series.first().unwrap(), series.last().unwrap());
+            let (first, last) = (*series.first().unwrap(), *series.last().unwrap());
             let abs_diff = last - first;
-            let first = if *first == 0.0 { 1.0 } else { *first };
+            let first = if first == T::zero() { T::one() } else { first };
             let rel_diff = abs_diff / first;
             Some((abs_diff, rel_diff))
         } else {
@@ -79,15 +212,15 @@ struct WindowedSMA {
 }
 
 #[async_trait]
-impl AsyncStockSignal for WindowedSMA {
-    type SignalType = Vec<f64>;
+impl<T: Numeric> AsyncStockSignal<T> for WindowedSMA {
+    type SignalType = Vec<T>;
 
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         if !series.is_empty() && self.window_size > 1 {
             Some(
                 series
                     .windows(self.window_size)
-                    .map(|w| w.iter().sum::<f64>() / w.len() as f64)
+                    .map(|w| w.iter().copied().sum::<T>() / T::from_usize(w.len()))
                     .collect(),
             )
         } else {
@@ -97,46 +230,129 @@ impl AsyncStockSignal for WindowedSMA {
 }
 
 ///
-/// Find the maximum in a series of f64
+/// Find the maximum in a series
 ///
 struct MaxPrice {}
 
 #[async_trait]
-impl AsyncStockSignal for MaxPrice {
-    type SignalType = f64;
-
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
-        if series.is_empty() {
-            None
-        } else {
-            Some(series.iter().fold(f64::MIN, |acc, q| acc.max(*q)))
-        }
+impl<T: Numeric> AsyncStockSignal<T> for MaxPrice {
+    type SignalType = T;
+
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
+        series
+            .iter()
+            .copied()
+            .reduce(|acc, q| if q > acc { q } else { acc })
     }
 }
 
 ///
-/// Find the maximum in a series of f64
+/// Find the minimum in a series
 ///
 struct MinPrice {}
 
 #[async_trait]
-impl AsyncStockSignal for MinPrice {
-    type SignalType = f64;
+impl<T: Numeric> AsyncStockSignal<T> for MinPrice {
+    type SignalType = T;
+
+    async fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
+        series
+            .iter()
+            .copied()
+            .reduce(|acc, q| if q < acc { q } else { acc })
+    }
+}
 
-    async fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
-        if series.is_empty() {
-            None
+///
+/// The quote field a price series is built from.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum PriceField {
+    Close,
+    Adjclose,
+    Open,
+    /// `(high + low + close) / 3`
+    Typical,
+}
+
+impl PriceField {
+    fn of(&self, quote: &yahoo::Quote) -> f64 {
+        match self {
+            PriceField::Close => quote.close,
+            PriceField::Adjclose => quote.adjclose,
+            PriceField::Open => quote.open,
+            PriceField::Typical => (quote.high + quote.low + quote.close) / 3.0,
+        }
+    }
+}
+
+///
+/// Adjustments applied to a price series. Yahoo's prices are already split-adjusted, so splits
+/// need no extra step.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Adjustment {
+    None,
+    /// Reinvest dividends on their ex-date to get a total-return series
+    TotalReturn,
+}
+
+impl Adjustment {
+    ///
+    /// Check that the adjustment can be applied to a field: Yahoo's `adjclose` already accounts for
+    /// dividends, reinvesting them again would count them twice.
+    ///
+    fn check(&self, field: PriceField) -> std::result::Result<(), String> {
+        if *self == Adjustment::TotalReturn && field == PriceField::Adjclose {
+            Err(
+                "adjclose already includes dividends, use total-return with another price \
+                 field like close"
+                    .to_string(),
+            )
         } else {
-            Some(series.iter().fold(f64::MAX, |acc, q| acc.min(*q)))
+            Ok(())
         }
     }
 }
 
+///
+/// Extract the price series from quotes (sorted by time, asc) and apply the adjustment. Dividends
+/// are expected to be on the same share basis as the quotes.
+///
+fn price_series(
+    quotes: &[yahoo::Quote],
+    dividends: &[yahoo::Dividend],
+    field: PriceField,
+    adjustment: Adjustment,
+) -> Vec<f64> {
+    let mut factor = 1.0;
+    let mut previous: Option<&yahoo::Quote> = None;
+    quotes
+        .iter()
+        .map(|q| {
+            if let (Adjustment::TotalReturn, Some(prev)) = (adjustment, previous) {
+                let prev_price = field.of(prev);
+                if prev_price != 0.0 {
+                    for d in dividends
+                        .iter()
+                        .filter(|d| d.date > prev.timestamp && d.date <= q.timestamp)
+                    {
+                        factor *= 1.0 + d.amount / prev_price;
+                    }
+                }
+            }
+            previous = Some(q);
+            field.of(q) * factor
+        })
+        .collect()
+}
+
 #[message]
 #[derive(Debug, Default, Clone)]
 struct Quotes {
     symbol: String,
     quotes: Vec<yahoo::Quote>,
+    dividends: Vec<yahoo::Dividend>,
 }
 
 #[message]
@@ -152,11 +368,11 @@ struct QuoteRequest {
 struct PerformanceIndicators {
     symbol: String,
     timestamp: DateTime<Utc>,
-    price: f64,
-    pct_change: f64,
-    period_min: f64,
-    period_max: f64,
-    last_sma: f64,
+    price: Price,
+    pct_change: Price,
+    period_min: Price,
+    period_max: Price,
+    last_sma: Price,
 }
 
 struct StockDataDownloader;
@@ -181,11 +397,12 @@ impl Handler<QuoteRequest> for StockDataDownloader {
                     Quotes {
                         symbol: symbol.clone(),
                         quotes,
+                        dividends: response.dividends().unwrap_or_default(),
                     }
                 } else {
                     Quotes {
                         symbol: symbol.clone(),
-                        quotes: vec![],
+                        ..Default::default()
                     }
                 }
             }
@@ -193,7 +410,7 @@ impl Handler<QuoteRequest> for StockDataDownloader {
                 eprintln!("Ignoring API error for symbol '{symbol}': {e}");
                 Quotes {
                     symbol: symbol.clone(),
-                    quotes: vec![],
+                    ..Default::default()
                 }
             }
         };
@@ -203,7 +420,16 @@ impl Handler<QuoteRequest> for StockDataDownloader {
     }
 }
 
-struct StockDataProcessor;
+#[async_trait]
+impl Handler<Drain> for StockDataDownloader {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {}
+}
+
+struct StockDataProcessor {
+    price_field: PriceField,
+    adjustment: Adjustment,
+    format: CsvFormat,
+}
 
 #[async_trait]
 impl Actor for StockDataProcessor {
@@ -220,19 +446,34 @@ impl Handler<Quotes> for StockDataProcessor {
 
             let last_date =
                 DateTime::from_timestamp(msg.quotes.last().unwrap().timestamp as i64, 0).unwrap();
-            let closes = msg.quotes.iter().map(|q| q.close).collect::<Vec<_>>();
+            // dropping a price would shift the window against the timestamps
+            let Some(prices) = price_series(
+                &msg.quotes,
+                &msg.dividends,
+                self.price_field,
+                self.adjustment,
+            )
+            .into_iter()
+            .map(Numeric::from_f64)
+            .collect::<Option<Vec<Price>>>() else {
+                eprintln!(
+                    "Ignoring the quotes of symbol '{}', a price can't be represented",
+                    msg.symbol
+                );
+                return;
+            };
 
             let diff = PriceDifference {};
             let min = MinPrice {};
             let max = MaxPrice {};
             let sma = WindowedSMA { window_size: 30 };
 
-            let period_max: f64 = max.calculate(&closes).await.unwrap();
-            let period_min: f64 = min.calculate(&closes).await.unwrap();
+            let period_max: Price = max.calculate(&prices).await.unwrap();
+            let period_min: Price = min.calculate(&prices).await.unwrap();
 
-            let last_price = *closes.last().unwrap();
-            let (_, pct_change) = diff.calculate(&closes).await.unwrap();
-            let sma = sma.calculate(&closes).await.unwrap();
+            let last_price = *prices.last().unwrap();
+            let (_, pct_change) = diff.calculate(&prices).await.unwrap();
+            let sma = sma.calculate(&prices).await.unwrap();
 
             let data = PerformanceIndicators {
                 timestamp: last_date,
@@ -241,114 +482,235 @@ impl Handler<Quotes> for StockDataProcessor {
                 pct_change,
                 period_min,
                 period_max,
-                last_sma: *sma.last().unwrap_or(&0.0),
+                last_sma: *sma.last().unwrap_or(&Price::zero()),
             };
 
+            println!("{}", self.format.line(&self.format.record(&data)));
             if let Err(e) = Broker::from_registry().await.unwrap().publish(data) {
                 eprintln!("{e}");
             }
-
-            println!(
-                "{},{},${:.2},{:.2}%,${:.2},${:.2},${:.2}",
-                last_date.to_rfc3339(),
-                msg.symbol,
-                last_price,
-                pct_change * 100.0,
-                period_min,
-                period_max,
-                sma.last().unwrap_or(&0.0)
-            )
         } else {
             println!("empty quotes");
         }
     }
 }
 
+#[async_trait]
+impl Handler<Drain> for StockDataProcessor {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {}
+}
+
+///
+/// Flush the file sink, sent by its timer if the flush policy has an interval
+///
+#[message]
+#[derive(Debug, Clone)]
+struct Flush;
+
 #[derive(Debug, Default)]
 struct FileSink {
-    filename: String,
-    writer: Option<BufWriter<File>>,
+    files: RotatingFiles,
+    format: CsvFormat,
+}
+
+impl FileSink {
+    fn header(&self) -> String {
+        format!("{}\n", self.format.line(&self.format.header()))
+    }
+
+    ///
+    /// Flush and close the files.
+    ///
+    fn close(&mut self) {
+        if let Err(e) = self.files.close() {
+            eprintln!("Couldn't flush {e}, data was lost");
+        }
+    }
 }
 
 #[async_trait]
 impl Actor for FileSink {
     async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
-        let mut file = File::create(&self.filename).unwrap();
-        let _ = writeln!(file, "period start,symbol,price,change %,min,max,30d avg");
-        self.writer = Some(BufWriter::new(file));
+        let header = self.header();
+        self.files.open(Utc::now(), header.as_bytes())?;
+        if let FlushPolicy::Interval(interval) = self.files.options.flush {
+            ctx.send_interval(Flush, interval);
+        }
         ctx.subscribe::<PerformanceIndicators>().await
     }
 
-    async fn stopped(&mut self, ctx: &mut Context<Self>) {
-        if let Some(writer) = &mut self.writer.take() {
-            writer.flush().unwrap();
+    async fn stopped(&mut self, _ctx: &mut Context<Self>) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Drain> for FileSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {
+        self.close();
+    }
+}
+
+#[async_trait]
+impl Handler<Flush> for FileSink {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Flush) {
+        if let Err(e) = self.files.flush() {
+            eprintln!("Couldn't flush {e}");
         }
-        ctx.stop(None);
     }
 }
 
 #[async_trait]
 impl Handler<PerformanceIndicators> for FileSink {
     async fn handle(&mut self, _ctx: &mut Context<Self>, msg: PerformanceIndicators) {
-        if let Some(file) = &mut self.writer {
-            let _ = writeln!(
-                file,
-                "{},{},${:.2},{:.2}%,${:.2},${:.2},${:.2}",
-                msg.timestamp.to_rfc3339(),
-                msg.symbol,
-                msg.price,
-                msg.pct_change * 100.0,
-                msg.period_min,
-                msg.period_max,
-                msg.last_sma,
-            );
-            file.flush().unwrap();
+        let record = format!("{}\n", self.format.line(&self.format.record(&msg)));
+        let header = self.header();
+        if let Err(e) = self.files.write(
+            &msg.symbol,
+            Utc::now(),
+            header.as_bytes(),
+            record.as_bytes(),
+        ) {
+            eprintln!("Couldn't write to {e}");
+        }
+    }
+}
+
+///
+/// Read `pause [SYMBOL]` and `resume [SYMBOL]` commands from stdin and pass them on to the scheduler
+///
+async fn read_commands() -> Result<()> {
+    let mut lines = io::BufReader::new(io::stdin()).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let (command, symbol) = (words.next(), words.next().map(|s| s.to_string()));
+        match command {
+            Some("pause") => Broker::from_registry().await?.publish(Pause(symbol))?,
+            Some("resume") => Broker::from_registry().await?.publish(Resume(symbol))?,
+            Some(other) => {
+                eprintln!("Unknown command '{other}', try 'pause [SYMBOL]' or 'resume [SYMBOL]'")
+            }
+            None => {}
         }
     }
+    Ok(())
+}
+
+///
+/// Stop scheduling and drain the pipeline stage by stage, brokers included, so every quote that
+/// was requested ends up in the file.
+///
+async fn drain(
+    scheduler: &Addr<Scheduler>,
+    downloader: &Addr<StockDataDownloader>,
+    processor: &Addr<StockDataProcessor>,
+    sink: &Addr<FileSink>,
+) -> Result<()> {
+    scheduler.call(Pause(None)).await?;
+    Broker::<QuoteRequest>::from_registry()
+        .await?
+        .call(Drain)
+        .await?;
+    downloader.call(Drain).await?;
+    Broker::<Quotes>::from_registry().await?.call(Drain).await?;
+    processor.call(Drain).await?;
+    Broker::<PerformanceIndicators>::from_registry()
+        .await?
+        .call(Drain)
+        .await?;
+    sink.call(Drain).await
 }
 
 #[xactor::main]
 async fn main() -> Result<()> {
     let opts = Opts::parse();
+    opts.adjust.check(opts.price_field).map_err(Error::msg)?;
     let from: DateTime<Utc> = opts.from.parse().expect("Couldn't parse 'from' date");
-    let to = Utc::now();
-
-    let _downloader = Supervisor::start(|| StockDataDownloader).await;
-    let _processor = Supervisor::start(|| StockDataProcessor).await;
-    let _sink = Supervisor::start(|| FileSink {
-        filename: "output.csv".to_string(),
-        ..Default::default()
+    let shutdown = shutdown::signals()?;
+
+    let downloader = Supervisor::start(|| StockDataDownloader).await?;
+    let (price_field, adjustment) = (opts.price_field, opts.adjust);
+    let format = CsvFormat {
+        delimiter: opts.delimiter,
+        precision: opts.precision,
+        pretty: opts.pretty,
+    };
+    let processor = Supervisor::start(move || StockDataProcessor {
+        price_field,
+        adjustment,
+        format,
+    })
+    .await?;
+    let output = OutputOptions {
+        append: opts.append,
+        rotation: opts.rotate,
+        gzip: opts.gzip,
+        flush: opts.flush,
+        fsync: opts.fsync,
+        ..OutputOptions::new(&opts.output)
+    };
+    let sink = Supervisor::start(move || FileSink {
+        files: RotatingFiles::new(output.clone()),
+        format,
     })
-    .await;
+    .await?;
 
-    let mut interval = stream::interval(Duration::from_secs(30));
     // a simple way to output a CSV header
-    println!("period start,symbol,price,change %,min,max,30d avg");
-    let symbols = opts.symbols.split(',').collect::<Vec<_>>();
-    'outer: while interval.next().await.is_some() {
-        for symbol in &symbols {
-            if let Err(e) = Broker::from_registry().await?.publish(QuoteRequest {
-                symbol: symbol.to_string(),
-                from,
-                to,
-            }) {
-                eprintln!("{e}");
-                break 'outer;
-            }
+    println!("{}", format.line(&format.header()));
+    let symbols = opts
+        .symbols
+        .split(',')
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let calendar = opts
+        .calendar
+        .as_ref()
+        .map(Calendar::from_file)
+        .transpose()?;
+    let post_close_refresh = opts.post_close_refresh;
+    let scheduler = Supervisor::start(move || {
+        Scheduler::new(
+            &symbols,
+            from,
+            Duration::from_secs(30),
+            calendar.clone(),
+            post_close_refresh,
+        )
+    })
+    .await?;
+
+    // stdin may close early, the actors keep going until a signal arrives
+    task::spawn(async {
+        if let Err(e) = read_commands().await {
+            eprintln!("Couldn't read commands: {e}");
+        }
+    });
+    shutdown.recv().await?;
+    eprintln!("Shutting down, press Ctrl-C again to exit right away");
+    let deadline = Duration::from_secs(opts.shutdown_timeout);
+    match future::timeout(deadline, drain(&scheduler, &downloader, &processor, &sink)).await {
+        Ok(result) => result,
+        Err(_) => {
+            eprintln!(
+                "Couldn't drain within {}s, unwritten data is lost",
+                deadline.as_secs()
+            );
+            Ok(())
         }
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     #![allow(non_snake_case)]
     use super::*;
+    use rust_decimal_macros::dec;
 
     #[async_std::test]
     async fn test_PriceDifference_calculate() {
         let signal = PriceDifference {};
-        assert_eq!(signal.calculate(&[]).await, None);
+        assert_eq!(signal.calculate(&[] as &[f64]).await, None);
         assert_eq!(signal.calculate(&[1.0]).await, Some((0.0, 0.0)));
         assert_eq!(signal.calculate(&[1.0, 0.0]).await, Some((-1.0, -1.0)));
         assert_eq!(
@@ -366,7 +728,7 @@ mod tests {
     #[async_std::test]
     async fn test_MinPrice_calculate() {
         let signal = MinPrice {};
-        assert_eq!(signal.calculate(&[]).await, None);
+        assert_eq!(signal.calculate(&[] as &[f64]).await, None);
         assert_eq!(signal.calculate(&[1.0]).await, Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]).await, Some(0.0));
         assert_eq!(
@@ -384,7 +746,7 @@ mod tests {
     #[async_std::test]
     async fn test_MaxPrice_calculate() {
         let signal = MaxPrice {};
-        assert_eq!(signal.calculate(&[]).await, None);
+        assert_eq!(signal.calculate(&[] as &[f64]).await, None);
         assert_eq!(signal.calculate(&[1.0]).await, Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]).await, Some(1.0));
         assert_eq!(
@@ -415,4 +777,127 @@ mod tests {
         let signal = WindowedSMA { window_size: 10 };
         assert_eq!(signal.calculate(&series).await, Some(vec![]));
     }
+
+    #[async_std::test]
+    async fn test_signals_calculate_Decimal() {
+        let series = vec![dec!(2.0), dec!(4.5), dec!(5.3), dec!(6.5), dec!(4.7)];
+
+        let signal = WindowedSMA { window_size: 3 };
+        assert_eq!(
+            signal.calculate(&series).await,
+            Some(vec![
+                dec!(3.9333333333333333333333333333),
+                dec!(5.4333333333333333333333333333),
+                dec!(5.5)
+            ])
+        );
+        assert_eq!(
+            PriceDifference {}.calculate(&series).await,
+            Some((dec!(2.7), dec!(1.35)))
+        );
+        assert_eq!(MinPrice {}.calculate(&series).await, Some(dec!(2.0)));
+        assert_eq!(MaxPrice {}.calculate(&series).await, Some(dec!(6.5)));
+    }
+
+    #[test]
+    fn test_Numeric_from_f64() {
+        assert_eq!(<f64 as Numeric>::from_f64(1.5), Some(1.5));
+        assert_eq!(<Decimal as Numeric>::from_f64(1.5), Some(dec!(1.5)));
+        assert_eq!(<Decimal as Numeric>::from_f64(f64::NAN), None);
+    }
+
+    #[test]
+    fn test_price_series() {
+        let quote = |timestamp, close| yahoo::Quote {
+            timestamp,
+            open: close - 1.0,
+            high: close + 1.0,
+            low: close - 2.0,
+            volume: 100,
+            close,
+            adjclose: close / 2.0,
+        };
+        let quotes = vec![quote(1, 10.0), quote(2, 10.0), quote(3, 12.0)];
+        let dividends = vec![yahoo::Dividend {
+            amount: 1.0,
+            date: 2,
+        }];
+
+        assert_eq!(
+            price_series(&quotes, &dividends, PriceField::Close, Adjustment::None),
+            vec![10.0, 10.0, 12.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Adjclose, Adjustment::None),
+            vec![5.0, 5.0, 6.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Typical, Adjustment::TotalReturn),
+            vec![29.0 / 3.0, 29.0 / 3.0, 35.0 / 3.0]
+        );
+        assert_eq!(
+            price_series(
+                &quotes,
+                &dividends,
+                PriceField::Close,
+                Adjustment::TotalReturn
+            ),
+            vec![10.0, 11.0, 13.200000000000001]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_FileSink_drain() {
+        let filename = std::env::temp_dir()
+            .join(format!("drain-{}.csv", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let sink = FileSink {
+            files: RotatingFiles::new(OutputOptions::new(&filename)),
+            ..Default::default()
+        }
+        .start()
+        .await
+        .unwrap();
+        let indicators = PerformanceIndicators {
+            symbol: "AAPL".to_string(),
+            timestamp: DateTime::from_timestamp(0, 0).unwrap(),
+            price: Price::one(),
+            pct_change: Price::from_f64(0.5).unwrap(),
+            period_min: Price::one(),
+            period_max: Price::from_usize(2),
+            last_sma: Price::from_f64(1.5).unwrap(),
+        };
+        let mut broker = Broker::from_registry().await.unwrap();
+        broker.publish(indicators.clone()).unwrap();
+        broker.publish(indicators).unwrap();
+
+        broker.call(Drain).await.unwrap();
+        sink.call(Drain).await.unwrap();
+        let content = std::fs::read_to_string(&filename).unwrap();
+        assert_eq!(content.lines().count(), 3);
+        assert!(content.ends_with("1970-01-01T00:00:00+00:00,AAPL,1,0.5,1,2,1.5\n"));
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn test_Adjustment_check() {
+        assert!(Adjustment::TotalReturn.check(PriceField::Close).is_ok());
+        assert!(Adjustment::TotalReturn.check(PriceField::Adjclose).is_err());
+        assert!(Adjustment::None.check(PriceField::Adjclose).is_ok());
+    }
+
+    #[test]
+    fn test_Opts_price_field() {
+        let parse = |args: &[&str]| {
+            Opts::parse_from(["app", "--from", "2020-01-01T00:00:00Z"].iter().chain(args))
+                .price_field
+        };
+        assert_eq!(parse(&[]), PriceField::Adjclose);
+        assert_eq!(parse(&["--adjust", "total-return"]), PriceField::Close);
+        assert_eq!(
+            parse(&["--adjust", "total-return", "--price-field", "open"]),
+            PriceField::Open
+        );
+    }
 }
@@ -0,0 +1,561 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use flate2::{write::GzEncoder, Compression};
+
+///
+/// When to start a new file. The full one is renamed to `<stem>.<time it was opened>.<ext>`.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    /// At midnight (UTC)
+    Daily,
+    /// Once a file reached this many bytes
+    Size(u64),
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("daily") {
+            return Ok(Rotation::Daily);
+        }
+        match parse_size(s) {
+            Some(size) if size > 0 => Ok(Rotation::Size(size)),
+            _ => Err(format!("'{}' is neither 'daily' nor a size like 10M", s)),
+        }
+    }
+}
+
+///
+/// Parse a number of bytes with an optional binary unit, e.g. `512`, `10K` or `5MB`.
+///
+pub fn parse_size(s: &str) -> Option<u64> {
+    let upper = s.trim().to_ascii_uppercase();
+    let digits = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let factor: u64 = match &upper[digits.len()..] {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(factor)
+}
+
+///
+/// When written records are flushed to the file, besides when it's closed or rotated.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FlushPolicy {
+    /// Only when the file is closed, e.g. on shutdown
+    #[default]
+    OnClose,
+    /// After this many records
+    Records(usize),
+    /// Every so often, when the owner calls `flush`
+    Interval(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "shutdown" {
+            return Ok(FlushPolicy::OnClose);
+        }
+        let policy = match s.strip_suffix('s') {
+            Some(seconds) => seconds
+                .parse()
+                .map(|n| FlushPolicy::Interval(Duration::from_secs(n))),
+            None => s.parse().map(FlushPolicy::Records),
+        };
+        match policy {
+            Ok(FlushPolicy::Interval(d)) if d.is_zero() => Err("the interval is zero".to_string()),
+            Ok(FlushPolicy::Records(0)) => Err("the number of records is zero".to_string()),
+            Ok(policy) => Ok(policy),
+            Err(_) => Err(format!(
+                "'{}' is neither a number of records, seconds like 5s nor 'shutdown'",
+                s
+            )),
+        }
+    }
+}
+
+///
+/// Where and how records are written. In the path template, `{date}` is replaced with the (UTC)
+/// date of writing, `{symbol}` with the symbol of a record, which gives one file per symbol, and
+/// `{timestamp}` with the Unix time the options were created.
+///
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    pub template: String,
+    /// Append to existing files instead of truncating them
+    pub append: bool,
+    pub rotation: Option<Rotation>,
+    /// Compress rotated files with gzip
+    pub gzip: bool,
+    pub flush: FlushPolicy,
+    /// Sync files to disk whenever they are flushed
+    pub fsync: bool,
+}
+
+impl OutputOptions {
+    pub fn new(template: &str) -> Self {
+        OutputOptions {
+            template: template.replace("{timestamp}", &Utc::now().timestamp().to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn per_symbol(&self) -> bool {
+        self.template.contains("{symbol}")
+    }
+
+    ///
+    /// The file of a symbol's records written at `now`.
+    ///
+    pub fn path(&self, symbol: &str, now: DateTime<Utc>) -> PathBuf {
+        // symbols like `A/B` shouldn't create directories
+        let symbol = symbol.replace(['/', '\\'], "_");
+        PathBuf::from(
+            self.template
+                .replace("{symbol}", &symbol)
+                .replace("{date}", &now.format("%Y-%m-%d").to_string()),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    opened: DateTime<Utc>,
+}
+
+///
+/// The files records are written to. They are opened when needed and rotated as configured; a
+/// header is only written to files that are new (or empty). A file that fails to write is closed
+/// and reopened with the next record. Rotated files are compressed in the background.
+///
+#[derive(Debug, Default)]
+pub struct RotatingFiles {
+    pub options: OutputOptions,
+    /// The open files by symbol, or by `""` if all symbols share one
+    files: HashMap<String, OpenFile>,
+    /// Files that were opened before, to only truncate them once
+    seen: HashSet<PathBuf>,
+    /// Records written since the last flush
+    unflushed: usize,
+    /// Rotated files being compressed
+    compressing: Vec<JoinHandle<()>>,
+}
+
+impl RotatingFiles {
+    pub fn new(options: OutputOptions) -> Self {
+        RotatingFiles {
+            options,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Open the file right away if all symbols share it, to find out early if that fails.
+    ///
+    pub fn open(&mut self, now: DateTime<Utc>, header: &[u8]) -> io::Result<()> {
+        if !self.options.per_symbol() {
+            self.file("", now, header)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Write a record of `symbol`, rotating its file first if that's due.
+    ///
+    pub fn write(
+        &mut self,
+        symbol: &str,
+        now: DateTime<Utc>,
+        header: &[u8],
+        record: &[u8],
+    ) -> io::Result<()> {
+        let file = self.file(symbol, now, header)?;
+        if let Err(e) = file.writer.write_all(record) {
+            let e = with_path(&file.path, e);
+            let key = self.key(symbol).to_string();
+            self.files.remove(&key);
+            return Err(e);
+        }
+        file.size += record.len() as u64;
+        self.unflushed += 1;
+        match self.options.flush {
+            FlushPolicy::Records(n) if self.unflushed >= n => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    ///
+    /// Flush all files, and sync them to disk if configured. Files that fail are closed, the error
+    /// of the last one is returned.
+    ///
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.unflushed = 0;
+        let fsync = self.options.fsync;
+        let mut result = Ok(());
+        self.files
+            .retain(|_, file| match sync(&mut file.writer, fsync) {
+                Ok(()) => true,
+                Err(e) => {
+                    result = Err(with_path(&file.path, e));
+                    false
+                }
+            });
+        result
+    }
+
+    ///
+    /// Flush and close all files, and wait for rotated ones to be compressed. Writing again appends
+    /// to them.
+    ///
+    pub fn close(&mut self) -> io::Result<()> {
+        let result = self.flush();
+        self.files.clear();
+        for compressing in self.compressing.drain(..) {
+            let _ = compressing.join();
+        }
+        result
+    }
+
+    fn file(
+        &mut self,
+        symbol: &str,
+        now: DateTime<Utc>,
+        header: &[u8],
+    ) -> io::Result<&mut OpenFile> {
+        let key = self.key(symbol);
+        let path = self.options.path(symbol, now);
+        if let Some(file) = self.files.remove(key) {
+            // e.g. the date changed, the file is done but keeps its name
+            let retire = file.path != path;
+            if retire || self.is_due(&file, now) {
+                // the record still gets written, to the same file if it couldn't be renamed
+                if let Err(e) = self.retire(file, !retire) {
+                    eprintln!("Couldn't rotate {}", e);
+                }
+            } else {
+                self.files.insert(key.to_string(), file);
+            }
+        }
+        if !self.files.contains_key(key) {
+            let file = self.create(path, now, header)?;
+            self.files.insert(key.to_string(), file);
+        }
+        Ok(self.files.get_mut(key).unwrap())
+    }
+
+    fn key<'a>(&self, symbol: &'a str) -> &'a str {
+        if self.options.per_symbol() {
+            symbol
+        } else {
+            ""
+        }
+    }
+
+    fn is_due(&self, file: &OpenFile, now: DateTime<Utc>) -> bool {
+        match self.options.rotation {
+            Some(Rotation::Daily) => file.opened.date_naive() != now.date_naive(),
+            Some(Rotation::Size(max)) => file.size >= max,
+            None => false,
+        }
+    }
+
+    fn create(&mut self, path: PathBuf, now: DateTime<Utc>, header: &[u8]) -> io::Result<OpenFile> {
+        let append = self.options.append || !self.seen.insert(path.clone());
+        let open = || -> io::Result<OpenFile> {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(&path)?;
+            let metadata = file.metadata()?;
+            let mut file = OpenFile {
+                path: path.clone(),
+                writer: BufWriter::new(file),
+                size: metadata.len(),
+                opened: now,
+            };
+            if file.size == 0 {
+                file.writer.write_all(header)?;
+                file.size = header.len() as u64;
+            } else if let Ok(modified) = metadata.modified() {
+                // appending to an older file, which may be due for rotation already
+                file.opened = modified.into();
+            }
+            Ok(file)
+        };
+        open().map_err(|e| with_path(&path, e))
+    }
+
+    ///
+    /// Close a file, and rename it out of the way if a new one takes its place. Both are
+    /// compressed if configured, on another thread so writing doesn't wait for it.
+    ///
+    fn retire(&mut self, file: OpenFile, rename: bool) -> io::Result<()> {
+        let OpenFile {
+            path,
+            mut writer,
+            opened,
+            ..
+        } = file;
+        sync(&mut writer, self.options.fsync).map_err(|e| with_path(&path, e))?;
+        drop(writer);
+        let path = if rename {
+            let rotated = rotated_path(&path, opened);
+            fs::rename(&path, &rotated).map_err(|e| with_path(&path, e))?;
+            rotated
+        } else {
+            path
+        };
+        if self.options.gzip {
+            self.compressing.retain(|c| !c.is_finished());
+            self.compressing.push(thread::spawn(move || {
+                if let Err(e) = gzip(&path) {
+                    eprintln!("Couldn't compress {}", with_path(&path, e));
+                }
+            }));
+        }
+        Ok(())
+    }
+}
+
+fn sync(writer: &mut BufWriter<File>, fsync: bool) -> io::Result<()> {
+    writer.flush()?;
+    if fsync {
+        writer.get_ref().sync_data()?;
+    }
+    Ok(())
+}
+
+fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("'{}': {}", path.display(), e))
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut gz = path.as_os_str().to_owned();
+    gz.push(".gz");
+    gz.into()
+}
+
+///
+/// `output.csv` opened on 2024-01-31 at 09:30 UTC becomes `output.2024-01-31T093000.csv`, with a
+/// counter added if that's taken.
+///
+fn rotated_path(path: &Path, opened: DateTime<Utc>) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let stamp = opened.format("%Y-%m-%dT%H%M%S");
+    (0..)
+        .map(|n| match n {
+            0 => path.with_file_name(format!("{}.{}{}", stem, stamp, extension)),
+            n => path.with_file_name(format!("{}.{}-{}{}", stem, stamp, n, extension)),
+        })
+        .find(|p| !p.exists() && !gz_path(p).exists())
+        .unwrap()
+}
+
+///
+/// Replace a file with its gzipped version `<path>.gz`.
+///
+fn gzip(path: &Path) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(gz_path(path))?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn list(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_Rotation_from_str() {
+        assert_eq!("daily".parse(), Ok(Rotation::Daily));
+        assert_eq!("1000".parse(), Ok(Rotation::Size(1000)));
+        assert_eq!("10k".parse(), Ok(Rotation::Size(10 * 1024)));
+        assert_eq!("5MB".parse(), Ok(Rotation::Size(5 * 1024 * 1024)));
+        assert!("0".parse::<Rotation>().is_err());
+        assert!("hourly".parse::<Rotation>().is_err());
+    }
+
+    #[test]
+    fn test_FlushPolicy_from_str() {
+        assert_eq!("shutdown".parse(), Ok(FlushPolicy::OnClose));
+        assert_eq!("100".parse(), Ok(FlushPolicy::Records(100)));
+        assert_eq!(
+            "5s".parse(),
+            Ok(FlushPolicy::Interval(Duration::from_secs(5)))
+        );
+        assert!("0".parse::<FlushPolicy>().is_err());
+        assert!("5m".parse::<FlushPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_OutputOptions_path() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 31, 9, 30, 0).unwrap();
+        let options = OutputOptions::new("out/{date}/{symbol}.csv");
+        assert_eq!(
+            options.path("BRK/B", now),
+            PathBuf::from("out/2024-01-31/BRK_B.csv")
+        );
+        assert!(!OutputOptions::new("{timestamp}.csv").template.contains('{'));
+    }
+
+    #[test]
+    fn test_RotatingFiles_append() {
+        let dir = temp_dir("append");
+        let now = Utc::now();
+        let options = OutputOptions::new(&dir.join("{symbol}.csv").to_string_lossy());
+        let mut files = RotatingFiles::new(options.clone());
+        files.write("AAPL", now, b"h\n", b"1\n").unwrap();
+        files.close().unwrap();
+        // closing doesn't truncate
+        files.write("AAPL", now, b"h\n", b"2\n").unwrap();
+        files.close().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("AAPL.csv")).unwrap(),
+            "h\n1\n2\n"
+        );
+
+        let mut files = RotatingFiles::new(OutputOptions {
+            append: true,
+            ..options.clone()
+        });
+        files.write("AAPL", now, b"h\n", b"3\n").unwrap();
+        files.write("MSFT", now, b"h\n", b"1\n").unwrap();
+        files.close().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("AAPL.csv")).unwrap(),
+            "h\n1\n2\n3\n"
+        );
+        assert_eq!(fs::read_to_string(dir.join("MSFT.csv")).unwrap(), "h\n1\n");
+
+        let mut files = RotatingFiles::new(options);
+        files.write("AAPL", now, b"h\n", b"4\n").unwrap();
+        files.close().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("AAPL.csv")).unwrap(), "h\n4\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_RotatingFiles_flush() {
+        let dir = temp_dir("flush");
+        let path = dir.join("out.csv");
+        let mut files = RotatingFiles::new(OutputOptions {
+            flush: FlushPolicy::Records(2),
+            fsync: true,
+            ..OutputOptions::new(&path.to_string_lossy())
+        });
+        files.write("AAPL", Utc::now(), b"h\n", b"1\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        files.write("AAPL", Utc::now(), b"h\n", b"2\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "h\n1\n2\n");
+        files.write("AAPL", Utc::now(), b"h\n", b"3\n").unwrap();
+        files.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "h\n1\n2\n3\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_RotatingFiles_rotation() {
+        let dir = temp_dir("rotation");
+        let day = Utc.with_ymd_and_hms(2024, 1, 31, 9, 30, 0).unwrap();
+        let mut files = RotatingFiles::new(OutputOptions {
+            rotation: Some(Rotation::Size(6)),
+            gzip: true,
+            ..OutputOptions::new(&dir.join("out.csv").to_string_lossy())
+        });
+        files.open(day, b"h\n").unwrap();
+        for record in [b"1\n", b"2\n", b"3\n"] {
+            files.write("AAPL", day, b"h\n", record).unwrap();
+        }
+        files.close().unwrap();
+        assert_eq!(list(&dir), vec!["out.2024-01-31T093000.csv.gz", "out.csv"]);
+        let mut rotated = String::new();
+        GzDecoder::new(File::open(dir.join("out.2024-01-31T093000.csv.gz")).unwrap())
+            .read_to_string(&mut rotated)
+            .unwrap();
+        assert_eq!(rotated, "h\n1\n2\n");
+        assert_eq!(fs::read_to_string(dir.join("out.csv")).unwrap(), "h\n3\n");
+
+        let mut files = RotatingFiles::new(OutputOptions {
+            rotation: Some(Rotation::Daily),
+            ..OutputOptions::new(&dir.join("daily.csv").to_string_lossy())
+        });
+        files.write("AAPL", day, b"", b"1\n").unwrap();
+        files.write("AAPL", day, b"", b"2\n").unwrap();
+        files
+            .write("AAPL", day + chrono::Duration::days(1), b"", b"3\n")
+            .unwrap();
+        files.close().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("daily.2024-01-31T093000.csv")).unwrap(),
+            "1\n2\n"
+        );
+        assert_eq!(fs::read_to_string(dir.join("daily.csv")).unwrap(), "3\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_RotatingFiles_rotation_failed() {
+        let dir = temp_dir("rotation-failed");
+        let path = dir.join("out.csv");
+        let mut files = RotatingFiles::new(OutputOptions {
+            rotation: Some(Rotation::Size(2)),
+            ..OutputOptions::new(&path.to_string_lossy())
+        });
+        files.write("AAPL", Utc::now(), b"", b"1\n").unwrap();
+        // the file can't be renamed, but the record that's due for rotation is kept
+        fs::remove_file(&path).unwrap();
+        files.write("AAPL", Utc::now(), b"h\n", b"2\n").unwrap();
+        files.close().unwrap();
+        assert_eq!(list(&dir), vec!["out.csv"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "h\n2\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
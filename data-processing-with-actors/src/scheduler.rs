@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use async_trait::async_trait;
+use chrono::prelude::*;
+use xactor::{message, Actor, Broker, Context, Handler, Result, Service};
+
+use crate::{
+    calendar::{Calendar, MarketSchedule},
+    QuoteRequest,
+};
+
+///
+/// Pause requesting quotes for a symbol, or for all symbols if `None`
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct Pause(pub Option<String>);
+
+///
+/// Resume requesting quotes for a symbol, or for all symbols if `None`. Runs missed while paused
+/// are caught up on right away.
+///
+#[message]
+#[derive(Debug, Clone)]
+pub struct Resume(pub Option<String>);
+
+#[message]
+#[derive(Debug, Clone)]
+struct Tick;
+
+#[derive(Debug)]
+struct SymbolSchedule {
+    next_run: DateTime<Utc>,
+    paused: bool,
+}
+
+///
+/// Actor that publishes `QuoteRequest`s for each symbol on its own schedule. The schedules are
+/// checked every second, so runs missed while the actor was busy (or the host asleep) are caught
+/// up on with a single request.
+///
+pub struct Scheduler {
+    from: DateTime<Utc>,
+    period: chrono::Duration,
+    market: MarketSchedule,
+    symbols: BTreeMap<String, SymbolSchedule>,
+}
+
+impl Scheduler {
+    pub fn new(
+        symbols: &[String],
+        from: DateTime<Utc>,
+        period: Duration,
+        calendar: Option<Calendar>,
+        post_close_refresh: bool,
+    ) -> Self {
+        let now = Utc::now();
+        Scheduler {
+            from,
+            period: chrono::Duration::from_std(period).unwrap_or(chrono::Duration::seconds(30)),
+            market: MarketSchedule::new(calendar, post_close_refresh),
+            symbols: symbols
+                .iter()
+                .map(|s| {
+                    let schedule = SymbolSchedule {
+                        next_run: now,
+                        paused: false,
+                    };
+                    (s.clone(), schedule)
+                })
+                .collect(),
+        }
+    }
+
+    ///
+    /// Collect the symbols that are due at `now` and move their next run past `now`.
+    ///
+    fn due(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let mut due = vec![];
+        for (symbol, schedule) in self.symbols.iter_mut() {
+            if schedule.paused || schedule.next_run > now {
+                continue;
+            }
+            // skip all missed runs, one request catches up on them
+            let missed = (now - schedule.next_run).num_milliseconds()
+                / self.period.num_milliseconds().max(1);
+            schedule.next_run += self.period * (missed as i32 + 1);
+            if self.market.is_due(symbol, now) {
+                due.push(symbol.clone());
+            }
+        }
+        due
+    }
+
+    fn set_paused(&mut self, symbol: Option<String>, paused: bool) {
+        for (_, schedule) in self
+            .symbols
+            .iter_mut()
+            .filter(|(s, _)| symbol.as_ref().is_none_or(|symbol| symbol == *s))
+        {
+            schedule.paused = paused;
+        }
+    }
+}
+
+#[async_trait]
+impl Actor for Scheduler {
+    async fn started(&mut self, ctx: &mut Context<Self>) -> Result<()> {
+        ctx.subscribe::<Pause>().await?;
+        ctx.subscribe::<Resume>().await?;
+        ctx.send_interval(Tick, Duration::from_secs(1));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Handler<Tick> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Tick) {
+        let now = Utc::now(); // Period end for this fetch
+        for symbol in self.due(now) {
+            let request = QuoteRequest {
+                symbol,
+                from: self.from,
+                to: now,
+            };
+            if let Err(e) = Broker::from_registry().await.unwrap().publish(request) {
+                eprintln!("{e}");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler<Pause> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Pause) {
+        self.set_paused(msg.0, true);
+    }
+}
+
+#[async_trait]
+impl Handler<Resume> for Scheduler {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, msg: Resume) {
+        self.set_paused(msg.0, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+    use super::*;
+
+    #[test]
+    fn test_Scheduler_due() {
+        let symbols = vec!["AAPL".to_string(), "MSFT".to_string()];
+        let mut scheduler =
+            Scheduler::new(&symbols, Utc::now(), Duration::from_secs(30), None, false);
+        let start = scheduler.symbols["AAPL"].next_run;
+
+        assert_eq!(scheduler.due(start), symbols);
+        assert!(scheduler
+            .due(start + chrono::Duration::seconds(29))
+            .is_empty());
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(30)),
+            symbols
+        );
+
+        // three missed runs are caught up on with one request
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(125)),
+            symbols
+        );
+        assert!(scheduler
+            .due(start + chrono::Duration::seconds(140))
+            .is_empty());
+        assert_eq!(
+            scheduler.symbols["AAPL"].next_run,
+            start + chrono::Duration::seconds(150)
+        );
+
+        scheduler.set_paused(Some("AAPL".to_string()), true);
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(150)),
+            vec!["MSFT".to_string()]
+        );
+        scheduler.set_paused(None, false);
+        assert_eq!(
+            scheduler.due(start + chrono::Duration::seconds(170)),
+            vec!["AAPL".to_string()]
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use async_std::channel::{self, Receiver};
+use async_trait::async_trait;
+use xactor::{message, Broker, Context, Handler, Message, Result};
+
+///
+/// Ask an actor to finish up. Actors handle their messages in order, so once a `Drain` call
+/// returns, everything sent to the actor before it has been handled. Sinks also flush and close
+/// their files.
+///
+#[message]
+#[derive(Debug, Clone, Copy)]
+pub struct Drain;
+
+///
+/// Brokers only pass messages on, draining one makes sure they all reached the subscribers.
+///
+#[async_trait]
+impl<T: Message<Result = ()>> Handler<Drain> for Broker<T> {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, _msg: Drain) {}
+}
+
+///
+/// Listen for SIGINT (Ctrl-C) and SIGTERM. The first signal is received on the returned channel to
+/// start a graceful shutdown, a second one exits right away.
+///
+pub fn signals() -> Result<Receiver<()>> {
+    let (tx, rx) = channel::bounded(1);
+    let mut received = false;
+    ctrlc::set_handler(move || {
+        if received {
+            eprintln!("Exiting without draining");
+            std::process::exit(130);
+        }
+        received = true;
+        let _ = tx.try_send(());
+    })?;
+    Ok(rx)
+}
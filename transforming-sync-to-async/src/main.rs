@@ -1,9 +1,24 @@
-use std::io::ErrorKind;
+use std::{
+    io::ErrorKind,
+    iter::Sum,
+    ops::{Add, Div, Sub},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 
 use chrono::prelude::{DateTime, Utc};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use rust_decimal::Decimal;
+use serde::Serialize;
 use yahoo_finance_api as yahoo;
 
+mod csv_format;
+use csv_format::{parse_delimiter, CsvFormat};
+
 #[derive(Parser, Debug)]
 #[clap(
     version = "1.0",
@@ -15,12 +30,111 @@ struct Opts {
     symbols: String,
     #[clap(short, long)]
     from: String,
+    /// The quote field to calculate the signals on, `close` with `--adjust total-return`
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = PriceField::Adjclose,
+        default_value_if("adjust", "total-return", "close")
+    )]
+    price_field: PriceField,
+    /// Adjustment applied to the price series
+    #[clap(long, value_enum, default_value_t = Adjustment::None)]
+    adjust: Adjustment,
+    /// Format of the printed records
+    #[clap(long, value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+    /// Field delimiter of the CSV output, a single character or `tab`
+    #[clap(long, value_parser = parse_delimiter, default_value = ",")]
+    delimiter: u8,
+    /// Decimals of the numbers in the CSV output, all of them by default. A raw `pct_change` is
+    /// never rounded
+    #[clap(long)]
+    precision: Option<usize>,
+    /// Write `$` prices and `%` changes rounded to 2 decimals instead of raw numbers
+    #[clap(long)]
+    pretty: bool,
+    /// Seconds to wait for the current download on Ctrl-C/SIGTERM
+    #[clap(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+}
+
+///
+/// The numeric type used for prices and signals. Enable the `decimal` feature for exact fixed-point
+/// arithmetic, e.g. to reconcile with accounting systems.
+///
+#[cfg(not(feature = "decimal"))]
+type Price = f64;
+#[cfg(feature = "decimal")]
+type Price = Decimal;
+
+///
+/// The numeric operations a signal needs to work on a series. Implemented for `f64` and for the
+/// fixed-point `Decimal`, which keeps the results exact.
+///
+trait Numeric:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Div<Output = Self>
+    + Sum
+    + Send
+    + Sync
+    + 'static
+{
+    fn zero() -> Self;
+
+    fn one() -> Self;
+
+    fn from_usize(n: usize) -> Self;
+
+    ///
+    /// Convert from an `f64`, `None` if the value can't be represented (e.g. `NaN`).
+    ///
+    fn from_f64(value: f64) -> Option<Self>;
+}
+
+impl Numeric for f64 {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn one() -> Self {
+        1.0
+    }
+
+    fn from_usize(n: usize) -> Self {
+        n as f64
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl Numeric for Decimal {
+    fn zero() -> Self {
+        Decimal::ZERO
+    }
+
+    fn one() -> Self {
+        Decimal::ONE
+    }
+
+    fn from_usize(n: usize) -> Self {
+        Decimal::from(n)
+    }
+
+    fn from_f64(value: f64) -> Option<Self> {
+        <Decimal as rust_decimal::prelude::FromPrimitive>::from_f64(value)
+    }
 }
 
 ///
 /// A trait to provide a common interface for all signal calculations.
 ///
-trait AsyncStockSignal {
+trait AsyncStockSignal<T: Numeric = f64> {
     ///
     /// The signal's data type.
     ///
@@ -33,22 +147,22 @@ trait AsyncStockSignal {
     ///
     /// The signal (using the provided type) or `None` on error/invalid data.
     ///
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType>;
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType>;
 }
 
 ///
-/// Calculates the absolute and relative difference between the beginning and ending of an f64 series. The relative difference is relative to the beginning.
+/// Calculates the absolute and relative difference between the beginning and ending of a series. The relative difference is relative to the beginning.
 ///
 /// # Returns
 ///
 /// A tuple `(absolute, relative)` difference.
 ///
-fn price_diff(a: &[f64]) -> Option<(f64, f64)> {
+fn price_diff<T: Numeric>(a: &[T]) -> Option<(T, T)> {
     if !a.is_empty() {
         // unwrap is safe here even if first == last
-        let (first, last) = (a.first().unwrap(), a.last().unwrap());
+        let (first, last) = (*a.first().unwrap(), *a.last().unwrap());
         let abs_diff = last - first;
-        let first = if *first == 0.0 { 1.0 } else { *first };
+        let first = if first == T::zero() { T::one() } else { first };
         let rel_diff = abs_diff / first;
         Some((abs_diff, rel_diff))
     } else {
@@ -59,12 +173,12 @@ fn price_diff(a: &[f64]) -> Option<(f64, f64)> {
 ///
 /// Window function to create a simple moving average
 ///
-fn n_window_sma(n: usize, series: &[f64]) -> Option<Vec<f64>> {
+fn n_window_sma<T: Numeric>(n: usize, series: &[T]) -> Option<Vec<T>> {
     if !series.is_empty() && n > 1 {
         Some(
             series
                 .windows(n)
-                .map(|w| w.iter().sum::<f64>() / w.len() as f64)
+                .map(|w| w.iter().copied().sum::<T>() / T::from_usize(w.len()))
                 .collect(),
         )
     } else {
@@ -73,52 +187,50 @@ fn n_window_sma(n: usize, series: &[f64]) -> Option<Vec<f64>> {
 }
 
 ///
-/// Find the maximum in a series of f64
+/// Find the maximum in a series
 ///
-fn max(series: &[f64]) -> Option<f64> {
-    if series.is_empty() {
-        None
-    } else {
-        Some(series.iter().fold(f64::MIN, |acc, q| acc.max(*q)))
-    }
+fn max<T: Numeric>(series: &[T]) -> Option<T> {
+    series
+        .iter()
+        .copied()
+        .reduce(|acc, q| if q > acc { q } else { acc })
 }
 
 ///
-/// Find the minimum in a series of f64
+/// Find the minimum in a series
 ///
-fn min(series: &[f64]) -> Option<f64> {
-    if series.is_empty() {
-        None
-    } else {
-        Some(series.iter().fold(f64::MAX, |acc, q| acc.min(*q)))
-    }
+fn min<T: Numeric>(series: &[T]) -> Option<T> {
+    series
+        .iter()
+        .copied()
+        .reduce(|acc, q| if q < acc { q } else { acc })
 }
 
 struct PriceDifference;
 
-impl AsyncStockSignal for PriceDifference {
-    type SignalType = (f64, f64);
+impl<T: Numeric> AsyncStockSignal<T> for PriceDifference {
+    type SignalType = (T, T);
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         price_diff(series)
     }
 }
 
 struct MinPrice;
 
-impl AsyncStockSignal for MinPrice {
-    type SignalType = f64;
+impl<T: Numeric> AsyncStockSignal<T> for MinPrice {
+    type SignalType = T;
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         min(series)
     }
 }
 struct MaxPrice;
 
-impl AsyncStockSignal for MaxPrice {
-    type SignalType = f64;
+impl<T: Numeric> AsyncStockSignal<T> for MaxPrice {
+    type SignalType = T;
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         max(series)
     }
 }
@@ -127,21 +239,143 @@ struct WindowedSMA {
     window_size: usize,
 }
 
-impl AsyncStockSignal for WindowedSMA {
-    type SignalType = Vec<f64>;
+impl<T: Numeric> AsyncStockSignal<T> for WindowedSMA {
+    type SignalType = Vec<T>;
 
-    fn calculate(&self, series: &[f64]) -> Option<Self::SignalType> {
+    fn calculate(&self, series: &[T]) -> Option<Self::SignalType> {
         n_window_sma(self.window_size, series)
     }
 }
 
 ///
-/// Retrieve data from a data source and extract the closing prices. Errors during download are mapped onto io::Errors as InvalidData.
+/// The quote field a price series is built from.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum PriceField {
+    Close,
+    Adjclose,
+    Open,
+    /// `(high + low + close) / 3`
+    Typical,
+}
+
+impl PriceField {
+    fn of(&self, quote: &yahoo::Quote) -> f64 {
+        match self {
+            PriceField::Close => quote.close,
+            PriceField::Adjclose => quote.adjclose,
+            PriceField::Open => quote.open,
+            PriceField::Typical => (quote.high + quote.low + quote.close) / 3.0,
+        }
+    }
+}
+
+///
+/// Adjustments applied to a price series. Yahoo's prices are already split-adjusted, so splits
+/// need no extra step.
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Adjustment {
+    None,
+    /// Reinvest dividends on their ex-date to get a total-return series
+    TotalReturn,
+}
+
+///
+/// Format of the printed records
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    Csv,
+    /// JSON Lines (NDJSON), one object per record
+    #[value(alias = "ndjson")]
+    Jsonl,
+}
+
+///
+/// Performance indicators of a symbol over the requested period
+///
+#[derive(Debug, Clone, Serialize)]
+struct PerformanceIndicators {
+    symbol: String,
+    period_start: DateTime<Utc>,
+    price: Price,
+    pct_change: Price,
+    period_min: Price,
+    period_max: Price,
+    last_sma: Price,
+}
+
+impl PerformanceIndicators {
+    fn print(&self, format: OutputFormat, csv: &CsvFormat) {
+        match format {
+            OutputFormat::Csv => println!("{}", csv.line(&csv.record(self))),
+            // plain strings, numbers and dates always serialize
+            OutputFormat::Jsonl => println!("{}", serde_json::to_string(self).unwrap()),
+        }
+    }
+}
+
+impl Adjustment {
+    ///
+    /// Check that the adjustment can be applied to a field: Yahoo's `adjclose` already accounts for
+    /// dividends, reinvesting them again would count them twice.
+    ///
+    fn check(&self, field: PriceField) -> Result<(), String> {
+        if *self == Adjustment::TotalReturn && field == PriceField::Adjclose {
+            Err(
+                "adjclose already includes dividends, use total-return with another price \
+                 field like close"
+                    .to_string(),
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+///
+/// Extract the price series from quotes (sorted by time, asc) and apply the adjustment. Dividends
+/// are expected to be on the same share basis as the quotes.
+///
+fn price_series(
+    quotes: &[yahoo::Quote],
+    dividends: &[yahoo::Dividend],
+    field: PriceField,
+    adjustment: Adjustment,
+) -> Vec<f64> {
+    let mut factor = 1.0;
+    let mut previous: Option<&yahoo::Quote> = None;
+    quotes
+        .iter()
+        .map(|q| {
+            if let (Adjustment::TotalReturn, Some(prev)) = (adjustment, previous) {
+                let prev_price = field.of(prev);
+                if prev_price != 0.0 {
+                    for d in dividends
+                        .iter()
+                        .filter(|d| d.date > prev.timestamp && d.date <= q.timestamp)
+                    {
+                        factor *= 1.0 + d.amount / prev_price;
+                    }
+                }
+            }
+            previous = Some(q);
+            field.of(q) * factor
+        })
+        .collect()
+}
+
+///
+/// Retrieve data from a data source and extract the (adjusted) price series. Errors during
+/// download are mapped onto io::Errors as InvalidData.
 ///
-async fn fetch_closing_data(
+async fn fetch_price_data(
     symbol: &str,
     beginning: &DateTime<Utc>,
     end: &DateTime<Utc>,
+    field: PriceField,
+    adjustment: Adjustment,
 ) -> std::io::Result<Vec<f64>> {
     let provider = yahoo::YahooConnector::new();
 
@@ -167,41 +401,92 @@ async fn fetch_closing_data(
     })?;
     if !quotes.is_empty() {
         quotes.sort_by_cached_key(|k| k.timestamp);
-        Ok(quotes.iter().map(|q| q.adjclose).collect())
+        let dividends = response.dividends().unwrap_or_default();
+        Ok(price_series(&quotes, &dividends, field, adjustment))
     } else {
         Ok(vec![])
     }
 }
 
+///
+/// Listen for SIGINT (Ctrl-C) and SIGTERM. The first signal sets the returned flag and leaves
+/// `deadline` to finish the current symbol, a second one exits right away.
+///
+fn shutdown_flag(deadline: Duration) -> std::io::Result<Arc<AtomicBool>> {
+    let flag = Arc::new(AtomicBool::new(false));
+    let signalled = flag.clone();
+    ctrlc::set_handler(move || {
+        if signalled.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Shutting down after the current symbol, press Ctrl-C again to exit right away");
+        thread::spawn(move || {
+            thread::sleep(deadline);
+            eprintln!("Couldn't finish within {}s", deadline.as_secs());
+            std::process::exit(130);
+        });
+    })
+    .map_err(std::io::Error::other)?;
+    Ok(flag)
+}
+
 #[async_std::main]
 async fn main() -> std::io::Result<()> {
     let opts = Opts::parse();
+    opts.adjust
+        .check(opts.price_field)
+        .map_err(std::io::Error::other)?;
     let from: DateTime<Utc> = opts.from.parse().expect("Couldn't parse 'from' date");
     let to = Utc::now();
-
-    // a simple way to output a CSV header
-    println!("period start,symbol,price,change %,min,max,30d avg");
+    let shutdown = shutdown_flag(Duration::from_secs(opts.shutdown_timeout))?;
+
+    let format = opts.format;
+    let csv = CsvFormat {
+        delimiter: opts.delimiter,
+        precision: opts.precision,
+        pretty: opts.pretty,
+    };
+    if format == OutputFormat::Csv {
+        println!("{}", csv.line(&csv.header()));
+    }
     for symbol in opts.symbols.split(',') {
-        let closes = fetch_closing_data(symbol, &from, &to).await?;
-        if !closes.is_empty() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let prices = fetch_price_data(symbol, &from, &to, opts.price_field, opts.adjust).await?;
+        // dropping a price would shift the window, so the symbol is skipped instead
+        let Some(prices) = prices
+            .into_iter()
+            .map(Numeric::from_f64)
+            .collect::<Option<Vec<Price>>>()
+        else {
+            eprintln!("Skipping '{}', a price can't be represented", symbol);
+            continue;
+        };
+        if !prices.is_empty() {
+            let diff = PriceDifference;
+            let min = MinPrice;
+            let max = MaxPrice;
+            let sma = WindowedSMA { window_size: 30 };
             // min/max of the period. unwrap() because those are Option types
-            let period_max: f64 = max(&closes).unwrap();
-            let period_min: f64 = min(&closes).unwrap();
-            let last_price = *closes.last().unwrap_or(&0.0);
-            let (_, pct_change) = price_diff(&closes).unwrap_or((0.0, 0.0));
-            let sma = n_window_sma(30, &closes).unwrap_or_default();
-
-            // a simple way to output CSV data
-            println!(
-                "{},{},${:.2},{:.2}%,${:.2},${:.2},${:.2}",
-                from.to_rfc3339(),
-                symbol,
-                last_price,
-                pct_change * 100.0,
+            let period_max: Price = max.calculate(&prices).unwrap();
+            let period_min: Price = min.calculate(&prices).unwrap();
+            let last_price = *prices.last().unwrap_or(&Price::zero());
+            let (_, pct_change) = diff
+                .calculate(&prices)
+                .unwrap_or((Price::zero(), Price::zero()));
+            let sma = sma.calculate(&prices).unwrap_or_default();
+
+            PerformanceIndicators {
+                symbol: symbol.to_string(),
+                period_start: from,
+                price: last_price,
+                pct_change,
                 period_min,
                 period_max,
-                sma.last().unwrap_or(&0.0)
-            );
+                last_sma: *sma.last().unwrap_or(&Price::zero()),
+            }
+            .print(format, &csv);
         }
     }
     Ok(())
@@ -211,11 +496,12 @@ async fn main() -> std::io::Result<()> {
 mod tests {
     #![allow(non_snake_case)]
     use super::*;
+    use rust_decimal_macros::dec;
 
     #[test]
     fn test_PriceDifference_calculate() {
         let signal = PriceDifference {};
-        assert_eq!(signal.calculate(&[]), None);
+        assert_eq!(signal.calculate(&[] as &[f64]), None);
         assert_eq!(signal.calculate(&[1.0]), Some((0.0, 0.0)));
         assert_eq!(signal.calculate(&[1.0, 0.0]), Some((-1.0, -1.0)));
         assert_eq!(
@@ -231,7 +517,7 @@ mod tests {
     #[test]
     fn test_MinPrice_calculate() {
         let signal = MinPrice {};
-        assert_eq!(signal.calculate(&[]), None);
+        assert_eq!(signal.calculate(&[] as &[f64]), None);
         assert_eq!(signal.calculate(&[1.0]), Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]), Some(0.0));
         assert_eq!(
@@ -247,7 +533,7 @@ mod tests {
     #[test]
     fn test_MaxPrice_calculate() {
         let signal = MaxPrice {};
-        assert_eq!(signal.calculate(&[]), None);
+        assert_eq!(signal.calculate(&[] as &[f64]), None);
         assert_eq!(signal.calculate(&[1.0]), Some(1.0));
         assert_eq!(signal.calculate(&[1.0, 0.0]), Some(1.0));
         assert_eq!(
@@ -276,4 +562,93 @@ mod tests {
         let signal = WindowedSMA { window_size: 10 };
         assert_eq!(signal.calculate(&series), Some(vec![]));
     }
+
+    #[test]
+    fn test_signals_calculate_Decimal() {
+        let series = vec![dec!(2.0), dec!(4.5), dec!(5.3), dec!(6.5), dec!(4.7)];
+
+        let signal = WindowedSMA { window_size: 3 };
+        assert_eq!(
+            signal.calculate(&series),
+            Some(vec![
+                dec!(3.9333333333333333333333333333),
+                dec!(5.4333333333333333333333333333),
+                dec!(5.5)
+            ])
+        );
+        assert_eq!(
+            PriceDifference {}.calculate(&series),
+            Some((dec!(2.7), dec!(1.35)))
+        );
+        assert_eq!(MinPrice {}.calculate(&series), Some(dec!(2.0)));
+        assert_eq!(MaxPrice {}.calculate(&series), Some(dec!(6.5)));
+    }
+
+    #[test]
+    fn test_Numeric_from_f64() {
+        assert_eq!(<f64 as Numeric>::from_f64(1.5), Some(1.5));
+        assert_eq!(<Decimal as Numeric>::from_f64(1.5), Some(dec!(1.5)));
+        assert_eq!(<Decimal as Numeric>::from_f64(f64::NAN), None);
+    }
+
+    #[test]
+    fn test_price_series() {
+        let quote = |timestamp, close| yahoo::Quote {
+            timestamp,
+            open: close - 1.0,
+            high: close + 1.0,
+            low: close - 2.0,
+            volume: 100,
+            close,
+            adjclose: close / 2.0,
+        };
+        let quotes = vec![quote(1, 10.0), quote(2, 10.0), quote(3, 12.0)];
+        let dividends = vec![yahoo::Dividend {
+            amount: 1.0,
+            date: 2,
+        }];
+
+        assert_eq!(
+            price_series(&quotes, &dividends, PriceField::Close, Adjustment::None),
+            vec![10.0, 10.0, 12.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Adjclose, Adjustment::None),
+            vec![5.0, 5.0, 6.0]
+        );
+        assert_eq!(
+            price_series(&quotes, &[], PriceField::Typical, Adjustment::TotalReturn),
+            vec![29.0 / 3.0, 29.0 / 3.0, 35.0 / 3.0]
+        );
+        assert_eq!(
+            price_series(
+                &quotes,
+                &dividends,
+                PriceField::Close,
+                Adjustment::TotalReturn
+            ),
+            vec![10.0, 11.0, 13.200000000000001]
+        );
+    }
+
+    #[test]
+    fn test_Adjustment_check() {
+        assert!(Adjustment::TotalReturn.check(PriceField::Close).is_ok());
+        assert!(Adjustment::TotalReturn.check(PriceField::Adjclose).is_err());
+        assert!(Adjustment::None.check(PriceField::Adjclose).is_ok());
+    }
+
+    #[test]
+    fn test_Opts_price_field() {
+        let parse = |args: &[&str]| {
+            Opts::parse_from(["app", "--from", "2020-01-01T00:00:00Z"].iter().chain(args))
+                .price_field
+        };
+        assert_eq!(parse(&[]), PriceField::Adjclose);
+        assert_eq!(parse(&["--adjust", "total-return"]), PriceField::Close);
+        assert_eq!(
+            parse(&["--adjust", "total-return", "--price-field", "open"]),
+            PriceField::Open
+        );
+    }
 }